@@ -1,3 +1,4 @@
+use crate::envelope_gen::StateChange as EnvelopeStateChange;
 use crate::patch::FixtureId;
 
 use number::UnipolarFloat;
@@ -25,6 +26,23 @@ impl Banks {
         }
     }
 
+    /// Add a bank to the collection.
+    /// If no bank is currently selected, select the new bank.
+    pub fn add(&mut self, bank: Bank) {
+        self.banks.push(bank);
+        if self.current_bank.is_none() {
+            self.current_bank = Some(self.banks.len() - 1);
+        }
+    }
+
+    /// Return the envelope overrides of the current bank.
+    /// Return no overrides if no bank is selected.
+    pub fn envelope_overrides(&self) -> &[EnvelopeStateChange] {
+        self.current_bank
+            .map(|id| self.banks[id].envelope.as_slice())
+            .unwrap_or(&[])
+    }
+
     /// Return the name of the current bank.
     pub fn current_bank(&self) -> Option<&str> {
         self.current_bank.map(|id| self.banks[id].name.as_ref())
@@ -38,9 +56,29 @@ pub struct Bank {
     name: String,
     sequences: Vec<PatternSequence>,
     current_sequence: Option<usize>,
+    /// Envelope parameters that replace those of the organ's envelope
+    /// generator while this bank is active.
+    #[serde(default)]
+    envelope: Vec<EnvelopeStateChange>,
 }
 
 impl Bank {
+    /// Create a bank that sends every event to the same set of fixtures.
+    pub fn fixture_set(name: String, fixtures: Vec<FixtureId>) -> Self {
+        Self {
+            name,
+            sequences: vec![PatternSequence::FixtureSet(fixtures)],
+            current_sequence: Some(0),
+            envelope: Vec::new(),
+        }
+    }
+
+    /// Replace the provided envelope parameters while this bank is active.
+    pub fn with_envelope(mut self, overrides: Vec<EnvelopeStateChange>) -> Self {
+        self.envelope = overrides;
+        self
+    }
+
     /// Next passes the fixture IDs in the next pattern to handler.
     /// Velocity will eventually be used to support velocity bucketing.
     pub fn next<T: UseFixtureId>(&mut self, _velocity: UnipolarFloat, handler: T) {
@@ -0,0 +1,348 @@
+//! Arbitrary multi-segment envelope definitions.
+
+use number::UnipolarFloat;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::edge::EdgeShape;
+use crate::envelope::{progress, EnvelopeParameters, EnvelopePhase, Loop, LoopMode, ReleasePolicy};
+
+/// One segment of a breakpoint envelope.
+/// A segment moves from the level at which the previous segment ended to its
+/// own target level over its duration.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Segment {
+    pub duration: Duration,
+    pub level: UnipolarFloat,
+    pub shape: EdgeShape,
+}
+
+impl Segment {
+    pub fn new(duration: Duration, level: UnipolarFloat, shape: EdgeShape) -> Self {
+        Self {
+            duration,
+            level,
+            shape,
+        }
+    }
+
+    /// Return the value of this segment at the provided time since the
+    /// segment began, starting from the provided level.
+    fn value(&self, from: UnipolarFloat, elapsed: Duration) -> UnipolarFloat {
+        self.shape
+            .transition(from, self.level, progress(elapsed, self.duration))
+    }
+}
+
+/// An envelope defined as a sequence of segments.
+///
+/// From note on, the envelope runs through the segments up to and including
+/// the sustain point, then holds the level of the sustain point until released.
+/// Once released, the envelope runs through the remaining segments, starting
+/// from whatever level it was at when the release began, and closes when they
+/// are complete.
+///
+/// If there is no sustain point, the envelope runs through every segment and
+/// then closes, regardless of release.  If the sustain level is zero, the
+/// envelope closes as soon as it reaches the sustain point.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Breakpoints {
+    /// Time between note on and the start of the first segment.
+    /// The envelope has a value of zero while delayed.
+    #[serde(default)]
+    pub delay: Duration,
+    /// The level of the envelope at note on.
+    pub start_level: UnipolarFloat,
+    pub segments: Vec<Segment>,
+    /// The index of the segment at the end of which the envelope holds.
+    pub sustain: Option<usize>,
+    pub release_policy: ReleasePolicy,
+    /// The minimum time after the delay before the release ramp may begin,
+    /// regardless of release policy.
+    #[serde(default)]
+    pub min_gate: Duration,
+    /// If provided, loop a portion of the segments before the sustain point
+    /// while held.
+    pub looping: Option<Loop>,
+}
+
+impl Breakpoints {
+    /// Return the segments that run before the sustain point.
+    fn held_segments(&self) -> &[Segment] {
+        &self.segments[..self.release_index()]
+    }
+
+    /// Return the segments that run after release.
+    fn release_segments(&self) -> &[Segment] {
+        &self.segments[self.release_index()..]
+    }
+
+    /// Return the index of the first release segment.
+    fn release_index(&self) -> usize {
+        match self.sustain {
+            Some(sustain) => (sustain + 1).min(self.segments.len()),
+            None => self.segments.len(),
+        }
+    }
+
+    /// Return the duration of the first segment.
+    /// This is considered the attack of the envelope.
+    pub fn attack(&self) -> Duration {
+        self.segments
+            .first()
+            .map(|s| s.duration)
+            .unwrap_or_else(|| Duration::from_secs(0))
+    }
+
+    /// Return the time from note on until the sustain point is reached,
+    /// ignoring looping.
+    pub fn sustain_point(&self) -> Duration {
+        self.held_segments().iter().map(|s| s.duration).sum()
+    }
+
+    /// Return the level held at the sustain point.
+    pub fn sustain_level(&self) -> UnipolarFloat {
+        self.held_segments()
+            .last()
+            .map(|s| s.level)
+            .unwrap_or(self.start_level)
+    }
+
+    /// Return the total duration of the release segments.
+    pub fn release_duration(&self) -> Duration {
+        self.release_segments().iter().map(|s| s.duration).sum()
+    }
+
+    /// Loop from the start of the provided segment through the sustain point.
+    pub fn set_sustain_loop(&mut self, first_segment: usize, count: Option<u32>, mode: LoopMode) {
+        let span = self.sustain_point().as_secs_f64();
+        let start: Duration = self
+            .held_segments()
+            .iter()
+            .take(first_segment)
+            .map(|s| s.duration)
+            .sum();
+        self.looping = Some(Loop {
+            start: if span == 0. {
+                UnipolarFloat::ZERO
+            } else {
+                UnipolarFloat::new(start.as_secs_f64() / span)
+            },
+            end: UnipolarFloat::ONE,
+            count,
+            mode,
+        });
+    }
+
+    /// Return the value of the envelope at the provided position before the
+    /// sustain point.
+    /// Return None if the envelope has closed.
+    pub(crate) fn held_value(&self, position: Duration) -> Option<UnipolarFloat> {
+        let mut level = self.start_level;
+        let mut segment_start = Duration::from_secs(0);
+        for segment in self.held_segments() {
+            let segment_end = segment_start + segment.duration;
+            // Segments include their endpoint, so zero-length segments
+            // take effect immediately.
+            if position <= segment_end {
+                return Some(segment.value(level, position - segment_start));
+            }
+            level = segment.level;
+            segment_start = segment_end;
+        }
+        // The sustain point has been reached.
+        if self.sustain.is_none() || level == UnipolarFloat::ZERO {
+            None
+        } else {
+            Some(level)
+        }
+    }
+
+    /// Return the phase of the envelope at the provided position before the
+    /// sustain point, and the progress through the current segment.
+    pub(crate) fn held_phase(&self, position: Duration) -> (EnvelopePhase, UnipolarFloat) {
+        let mut level = self.start_level;
+        let mut segment_start = Duration::from_secs(0);
+        for segment in self.held_segments() {
+            let segment_end = segment_start + segment.duration;
+            if position <= segment_end {
+                let phase = if segment.level > level {
+                    EnvelopePhase::Attack
+                } else if segment.level == level {
+                    EnvelopePhase::Hold
+                } else {
+                    EnvelopePhase::Decay
+                };
+                return (phase, progress(position - segment_start, segment.duration));
+            }
+            level = segment.level;
+            segment_start = segment_end;
+        }
+        (EnvelopePhase::Sustain, UnipolarFloat::ZERO)
+    }
+
+    /// Return the value of the envelope at the provided time since the release
+    /// began, starting from the provided level.
+    /// Return None if the envelope has closed.
+    pub(crate) fn release_value(
+        &self,
+        from: UnipolarFloat,
+        release_elapsed: Duration,
+    ) -> Option<UnipolarFloat> {
+        let mut level = from;
+        let mut segment_start = Duration::from_secs(0);
+        for segment in self.release_segments() {
+            let segment_end = segment_start + segment.duration;
+            // The envelope closes at the very end of the release.
+            if release_elapsed < segment_end {
+                return Some(segment.value(level, release_elapsed - segment_start));
+            }
+            level = segment.level;
+            segment_start = segment_end;
+        }
+        None
+    }
+}
+
+/// An ADSR envelope is a breakpoint envelope with attack, hold, decay and
+/// release segments that sustains at the end of the decay.
+impl From<EnvelopeParameters> for Breakpoints {
+    fn from(params: EnvelopeParameters) -> Self {
+        let peak = params.peak;
+        Self {
+            delay: params.delay,
+            start_level: params.attack_level * peak,
+            segments: vec![
+                Segment::new(params.attack, peak, params.attack_shape),
+                Segment::new(params.hold, peak, EdgeShape::Linear),
+                Segment::new(
+                    params.decay,
+                    params.sustain_level * peak,
+                    params.decay_shape,
+                ),
+                Segment::new(params.release, UnipolarFloat::ZERO, params.release_shape),
+            ],
+            sustain: Some(2),
+            release_policy: params.release_policy,
+            min_gate: params.min_gate,
+            looping: params.looping,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::envelope::Envelope;
+
+    fn linear(millis: u64, level: f64) -> Segment {
+        Segment::new(
+            Duration::from_millis(millis),
+            UnipolarFloat::new(level),
+            EdgeShape::Linear,
+        )
+    }
+
+    /// Return a double-flash envelope that sustains at half brightness and
+    /// releases in two stages.
+    fn double_flash() -> Breakpoints {
+        Breakpoints {
+            delay: Duration::from_secs(0),
+            start_level: UnipolarFloat::ZERO,
+            segments: vec![
+                linear(0, 1.0),
+                linear(100, 0.0),
+                linear(0, 1.0),
+                linear(100, 0.5),
+                linear(100, 0.25),
+                linear(100, 0.0),
+            ],
+            sustain: Some(3),
+            release_policy: ReleasePolicy::AfterDecay,
+            min_gate: Duration::from_secs(0),
+            looping: None,
+        }
+    }
+
+    #[test]
+    fn test_double_flash() {
+        let mut envelope = Envelope::new(double_flash());
+        assert_eq!(Some(UnipolarFloat::ONE), envelope.value());
+        envelope.update_state(Duration::from_millis(50));
+        assert_eq!(Some(UnipolarFloat::new(0.5)), envelope.value());
+        envelope.update_state(Duration::from_millis(50));
+        assert_eq!(Some(UnipolarFloat::ZERO), envelope.value());
+
+        // Second flash.
+        envelope.update_state(Duration::from_millis(50));
+        assert_eq!(Some(UnipolarFloat::new(0.75)), envelope.value());
+        envelope.update_state(Duration::from_secs(1));
+        assert_eq!(Some(UnipolarFloat::new(0.5)), envelope.value());
+
+        // Two-stage release.
+        envelope.release();
+        envelope.update_state(Duration::from_millis(100));
+        assert_eq!(Some(UnipolarFloat::new(0.25)), envelope.value());
+        envelope.update_state(Duration::from_millis(50));
+        assert_eq!(Some(UnipolarFloat::new(0.125)), envelope.value());
+        envelope.update_state(Duration::from_millis(50));
+        assert_eq!(None, envelope.value());
+    }
+
+    #[test]
+    /// An envelope without a sustain point should close on its own.
+    fn test_no_sustain() {
+        let mut breakpoints = double_flash();
+        breakpoints.sustain = None;
+        let mut envelope = Envelope::new(breakpoints);
+        envelope.update_state(Duration::from_millis(350));
+        assert_eq!(Some(UnipolarFloat::new(0.125)), envelope.value());
+        envelope.update_state(Duration::from_millis(50));
+        assert_eq!(Some(UnipolarFloat::ZERO), envelope.value());
+        envelope.update_state(Duration::from_nanos(1));
+        assert_eq!(None, envelope.value());
+    }
+
+    #[test]
+    /// Releasing an envelope without a sustain point has no effect, even with
+    /// an immediate release policy.
+    fn test_no_sustain_immediate_release() {
+        let mut breakpoints = double_flash();
+        breakpoints.sustain = None;
+        breakpoints.release_policy = ReleasePolicy::Immediate;
+        let mut envelope = Envelope::new(breakpoints);
+        envelope.update_state(Duration::from_millis(50));
+        envelope.release();
+        envelope.update_state(Duration::from_millis(300));
+        assert_eq!(Some(UnipolarFloat::new(0.125)), envelope.value());
+        assert_eq!(Some(Duration::from_millis(400)), envelope.total_duration());
+    }
+
+    #[test]
+    /// Loop the second flash for as long as the envelope is held.
+    fn test_sustain_loop() {
+        let mut breakpoints = double_flash();
+        breakpoints.set_sustain_loop(2, None, LoopMode::Restart);
+        let mut envelope = Envelope::new(breakpoints);
+        envelope.update_state(Duration::from_millis(200));
+        assert_eq!(Some(UnipolarFloat::new(0.5)), envelope.value());
+        envelope.update_state(Duration::from_millis(50));
+        assert_eq!(Some(UnipolarFloat::new(0.75)), envelope.value());
+        envelope.update_state(Duration::from_millis(1000));
+        assert_eq!(Some(UnipolarFloat::new(0.75)), envelope.value());
+    }
+
+    #[test]
+    fn test_adsr_preset() {
+        let breakpoints = Breakpoints::from(EnvelopeParameters::linear(
+            Duration::from_secs(1),
+            UnipolarFloat::ZERO,
+            Duration::from_secs(2),
+            UnipolarFloat::new(0.5),
+            Duration::from_secs(3),
+        ));
+        assert_eq!(Duration::from_secs(1), breakpoints.attack());
+        assert_eq!(Duration::from_secs(3), breakpoints.sustain_point());
+        assert_eq!(Duration::from_secs(3), breakpoints.release_duration());
+    }
+}
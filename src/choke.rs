@@ -0,0 +1,233 @@
+//! Choke groups of mutually exclusive notes.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+
+use crate::event::ReleaseID;
+use crate::organ::{EmitStateChange as EmitOrganStateChange, StateChange as OrganStateChange};
+
+/// What happens to the sounding events in a choke group when another note in
+/// the group starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChokeMode {
+    /// Release the events, so they fade out with their release.
+    Release,
+    /// Close the events immediately.
+    Cut,
+}
+
+/// A group of notes that choke each other, like an open and closed hi-hat.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChokeGroup {
+    /// The ranges of release IDs in this group.
+    members: Vec<RangeInclusive<ReleaseID>>,
+    mode: ChokeMode,
+}
+
+impl ChokeGroup {
+    /// Create a choke group of the provided release IDs.
+    pub fn new(release_ids: &[ReleaseID], mode: ChokeMode) -> Self {
+        Self {
+            members: release_ids.iter().map(|id| *id..=*id).collect(),
+            mode,
+        }
+    }
+
+    /// Add a range of release IDs, such as a range of notes, to this group.
+    pub fn with_range(mut self, range: RangeInclusive<ReleaseID>) -> Self {
+        self.members.push(range);
+        self
+    }
+
+    /// Remove a range of release IDs from this group.
+    pub fn clear(&mut self, range: RangeInclusive<ReleaseID>) {
+        let (start, end) = (*range.start(), *range.end());
+        let mut members = Vec::new();
+        for member in self.members.drain(..) {
+            if *member.end() < start || *member.start() > end {
+                members.push(member);
+                continue;
+            }
+            // Keep whatever lies on either side of the cleared range.
+            if *member.start() < start {
+                members.push(*member.start()..=start - 1);
+            }
+            if *member.end() > end {
+                members.push(end + 1..=*member.end());
+            }
+        }
+        self.members = members;
+    }
+
+    /// Return true if this group has no members.
+    pub fn is_empty(&self) -> bool {
+        self.members.iter().all(|range| range.is_empty())
+    }
+
+    /// Return true if this group contains the provided release ID.
+    pub fn contains(&self, release_id: ReleaseID) -> bool {
+        self.members.iter().any(|range| range.contains(&release_id))
+    }
+
+    pub fn mode(&self) -> ChokeMode {
+        self.mode
+    }
+}
+
+/// The collection of named choke groups defined for a color organ.
+#[derive(Serialize, Deserialize)]
+pub struct ChokeGroups(BTreeMap<String, ChokeGroup>);
+
+impl ChokeGroups {
+    pub fn new() -> Self {
+        Self(BTreeMap::new())
+    }
+
+    /// Return the group with the provided name, if there is one.
+    pub fn get(&self, name: &str) -> Option<&ChokeGroup> {
+        self.0.get(name)
+    }
+
+    /// Call handler with each group that a note with the provided release ID
+    /// chokes.
+    pub fn choked_by<T: FnMut(&ChokeGroup)>(&self, release_id: ReleaseID, mut handler: T) {
+        for group in self.0.values().filter(|g| g.contains(release_id)) {
+            handler(group);
+        }
+    }
+
+    /// Emit all observable state using the provided emitter.
+    pub fn emit_state<E: EmitStateChange>(&self, emitter: &mut E) {
+        for (name, group) in &self.0 {
+            emitter.emit_choke_state_change(StateChange::Group(name.clone(), Some(group.clone())));
+        }
+    }
+
+    /// Handle a control message.
+    /// Assigning release IDs or a mode to a group that doesn't exist creates
+    /// it, releasing by default.
+    pub fn control<E: EmitStateChange>(&mut self, msg: ControlMessage, emitter: &mut E) {
+        use ControlMessage::*;
+        let name = match msg {
+            Assign(name, range) => {
+                self.group_mut(&name).members.push(range);
+                name
+            }
+            Clear(name, range) => {
+                match self.0.get_mut(&name) {
+                    Some(group) => group.clear(range),
+                    None => return,
+                }
+                name
+            }
+            SetMode(name, mode) => {
+                self.group_mut(&name).mode = mode;
+                name
+            }
+            Delete(name) => {
+                if self.0.remove(&name).is_none() {
+                    return;
+                }
+                name
+            }
+        };
+        let group = self.0.get(&name).cloned();
+        emitter.emit_choke_state_change(StateChange::Group(name, group));
+    }
+
+    fn group_mut(&mut self, name: &str) -> &mut ChokeGroup {
+        self.0
+            .entry(name.to_string())
+            .or_insert_with(|| ChokeGroup::new(&[], ChokeMode::Release))
+    }
+}
+
+pub enum ControlMessage {
+    /// Add a range of release IDs to the named group.  Use a range of one to
+    /// add a single release ID.
+    Assign(String, RangeInclusive<ReleaseID>),
+    /// Remove a range of release IDs from the named group.
+    Clear(String, RangeInclusive<ReleaseID>),
+    SetMode(String, ChokeMode),
+    Delete(String),
+}
+
+pub enum StateChange {
+    /// The current definition of the named group, or None if it was deleted.
+    Group(String, Option<ChokeGroup>),
+}
+
+pub trait EmitStateChange {
+    fn emit_choke_state_change(&mut self, sc: StateChange);
+}
+
+impl<T: EmitOrganStateChange> EmitStateChange for T {
+    fn emit_choke_state_change(&mut self, sc: StateChange) {
+        self.emit_state_change(OrganStateChange::Choke(sc));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::organ::Discard;
+
+    fn assign(groups: &mut ChokeGroups, name: &str, range: RangeInclusive<ReleaseID>) {
+        groups.control(
+            ControlMessage::Assign(name.to_string(), range),
+            &mut Discard,
+        );
+    }
+
+    #[test]
+    fn test_membership() {
+        let mut groups = ChokeGroups::new();
+        assign(&mut groups, "hats", 42..=42);
+        assign(&mut groups, "hats", 46..=46);
+        groups.control(
+            ControlMessage::SetMode("hats".to_string(), ChokeMode::Cut),
+            &mut Discard,
+        );
+        assign(&mut groups, "toms", 40..=46);
+
+        let mut modes = Vec::new();
+        groups.choked_by(46, |g| modes.push(g.mode()));
+        assert_eq!(vec![ChokeMode::Cut, ChokeMode::Release], modes);
+
+        let mut modes = Vec::new();
+        groups.choked_by(44, |g| modes.push(g.mode()));
+        assert_eq!(vec![ChokeMode::Release], modes);
+
+        let mut count = 0;
+        groups.choked_by(47, |_| count += 1);
+        assert_eq!(0, count);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut groups = ChokeGroups::new();
+        assign(&mut groups, "toms", 40..=50);
+        groups.control(
+            ControlMessage::Clear("toms".to_string(), 44..=45),
+            &mut Discard,
+        );
+        let toms = groups.get("toms").unwrap();
+        assert!(toms.contains(40));
+        assert!(toms.contains(43));
+        assert!(!toms.contains(44));
+        assert!(!toms.contains(45));
+        assert!(toms.contains(46));
+        assert!(toms.contains(50));
+
+        groups.control(
+            ControlMessage::Clear("toms".to_string(), 0..=100),
+            &mut Discard,
+        );
+        assert!(groups.get("toms").unwrap().is_empty());
+
+        groups.control(ControlMessage::Delete("toms".to_string()), &mut Discard);
+        assert!(groups.get("toms").is_none());
+    }
+}
@@ -0,0 +1,231 @@
+//! A musical tempo clock, settable by tap tempo.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::organ::{EmitStateChange as EmitOrganStateChange, StateChange as OrganStateChange};
+
+/// Taps further apart than this start a new tap tempo measurement.
+const TAP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The range of tempos the clock accepts, in beats per minute.
+const MIN_BPM: f64 = 1.;
+const MAX_BPM: f64 = 999.;
+
+/// The maximum number of tap intervals averaged to estimate the tempo.
+const MAX_TAP_INTERVALS: usize = 4;
+
+/// Track the current musical tempo.
+/// Time is advanced using state updates, and taps are measured against it.
+pub struct Clock {
+    bpm: f64,
+    elapsed: Duration,
+    /// The times of the taps in the current tap tempo measurement.
+    taps: Vec<Duration>,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            bpm: 120.,
+            elapsed: Duration::from_secs(0),
+            taps: Vec::new(),
+        }
+    }
+
+    /// Return the duration of one beat at the current tempo.
+    pub fn beat(&self) -> Duration {
+        Duration::from_secs_f64(60. / self.bpm)
+    }
+
+    /// Return the duration of the provided note division at the current tempo.
+    pub fn duration(&self, division: NoteDivision) -> Duration {
+        self.beat().mul_f64(division.beats())
+    }
+
+    /// Update the state of this clock.
+    pub fn update_state(&mut self, delta_t: Duration) {
+        self.elapsed += delta_t;
+    }
+
+    /// Register a tap at the current time.
+    /// Set the tempo from the average interval between recent taps.
+    /// Return true if the tempo changed.
+    fn tap(&mut self) -> bool {
+        if let Some(last) = self.taps.last() {
+            if self.elapsed - *last > TAP_TIMEOUT {
+                self.taps.clear();
+            }
+        }
+        self.taps.push(self.elapsed);
+        if self.taps.len() > MAX_TAP_INTERVALS + 1 {
+            self.taps.remove(0);
+        }
+        if self.taps.len() < 2 {
+            return false;
+        }
+        let span = *self.taps.last().unwrap() - self.taps[0];
+        let interval = span.as_secs_f64() / (self.taps.len() - 1) as f64;
+        if interval <= 0. {
+            return false;
+        }
+        self.bpm = (60. / interval).clamp(MIN_BPM, MAX_BPM);
+        true
+    }
+
+    /// Emit all observable state using the provided emitter.
+    pub fn emit_state<E: EmitStateChange>(&self, emitter: &mut E) {
+        emitter.emit_clock_state_change(StateChange::Bpm(self.bpm));
+    }
+
+    /// Handle a control message.
+    pub fn control<E: EmitStateChange>(&mut self, msg: ControlMessage, emitter: &mut E) {
+        use ControlMessage::*;
+        match msg {
+            Set(sc) => self.handle_state_change(sc, emitter),
+            Tap => {
+                if self.tap() {
+                    emitter.emit_clock_state_change(StateChange::Bpm(self.bpm));
+                }
+            }
+        }
+    }
+
+    fn handle_state_change<E: EmitStateChange>(&mut self, sc: StateChange, emitter: &mut E) {
+        use StateChange::*;
+        match sc {
+            Bpm(v) => {
+                if !v.is_finite() || v <= 0. {
+                    return;
+                }
+                self.bpm = v.clamp(MIN_BPM, MAX_BPM);
+            }
+        };
+        emitter.emit_clock_state_change(Bpm(self.bpm));
+    }
+}
+
+/// A musical note length, relative to the clock's beat.
+/// A beat is a quarter note.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoteDivision {
+    /// The number of notes.
+    pub count: u32,
+    /// The note value as a fraction of a whole note; 4 is a quarter note.
+    pub note: u32,
+    pub modifier: NoteModifier,
+}
+
+impl NoteDivision {
+    pub fn new(count: u32, note: u32, modifier: NoteModifier) -> Self {
+        Self {
+            count,
+            note,
+            modifier,
+        }
+    }
+
+    /// Return the length of this division in beats.
+    pub fn beats(&self) -> f64 {
+        if self.note == 0 {
+            return 0.;
+        }
+        let straight = 4. * self.count as f64 / self.note as f64;
+        match self.modifier {
+            NoteModifier::Straight => straight,
+            NoteModifier::Dotted => straight * 1.5,
+            NoteModifier::Triplet => straight * 2. / 3.,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NoteModifier {
+    Straight,
+    Dotted,
+    Triplet,
+}
+
+pub enum ControlMessage {
+    Set(StateChange),
+    Tap,
+}
+
+pub enum StateChange {
+    Bpm(f64),
+}
+
+pub trait EmitStateChange {
+    fn emit_clock_state_change(&mut self, sc: StateChange);
+}
+
+impl<T: EmitOrganStateChange> EmitStateChange for T {
+    fn emit_clock_state_change(&mut self, sc: StateChange) {
+        self.emit_state_change(OrganStateChange::Clock(sc));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::organ::Discard;
+
+    #[test]
+    fn test_note_division() {
+        let mut clock = Clock::new();
+        clock.control(ControlMessage::Set(StateChange::Bpm(60.)), &mut Discard);
+        let quarter = NoteDivision::new(1, 4, NoteModifier::Straight);
+        assert_eq!(Duration::from_secs(1), clock.duration(quarter));
+        let dotted_half = NoteDivision::new(1, 2, NoteModifier::Dotted);
+        assert_eq!(Duration::from_secs(3), clock.duration(dotted_half));
+        let eighth_triplets = NoteDivision::new(3, 8, NoteModifier::Triplet);
+        assert_eq!(Duration::from_secs(1), clock.duration(eighth_triplets));
+    }
+
+    fn assert_beat(expected: Duration, clock: &Clock) {
+        let actual = clock.beat();
+        assert!(
+            (expected.as_secs_f64() - actual.as_secs_f64()).abs() < 1e-6,
+            "expected {:?}, got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_tap_tempo() {
+        let mut clock = Clock::new();
+        for _ in 0..4 {
+            clock.control(ControlMessage::Tap, &mut Discard);
+            clock.update_state(Duration::from_millis(400));
+        }
+        assert_beat(Duration::from_millis(400), &clock);
+
+        // A long pause starts a new measurement.
+        clock.update_state(Duration::from_secs(10));
+        for _ in 0..2 {
+            clock.control(ControlMessage::Tap, &mut Discard);
+            clock.update_state(Duration::from_millis(500));
+        }
+        assert_beat(Duration::from_millis(500), &clock);
+    }
+
+    #[test]
+    /// Invalid tempos are ignored, and extreme tempos are clamped.
+    fn test_bpm_range() {
+        let mut clock = Clock::new();
+        clock.control(
+            ControlMessage::Set(StateChange::Bpm(f64::NAN)),
+            &mut Discard,
+        );
+        clock.control(
+            ControlMessage::Set(StateChange::Bpm(f64::INFINITY)),
+            &mut Discard,
+        );
+        assert_eq!(Duration::from_millis(500), clock.beat());
+        clock.control(ControlMessage::Set(StateChange::Bpm(1e-300)), &mut Discard);
+        assert_eq!(Duration::from_secs(60), clock.beat());
+        clock.control(ControlMessage::Set(StateChange::Bpm(1e6)), &mut Discard);
+        assert_eq!(Duration::from_secs_f64(60. / 999.), clock.beat());
+    }
+}
@@ -1,15 +1,54 @@
 use std::{cell::Cell, f64::consts::PI};
 
 use number::{Phase, UnipolarFloat};
+use serde::{Deserialize, Serialize};
+
+use crate::hsluv;
+use crate::rgb::RgbColor;
 
 const TWOPI: f64 = 2.0 * PI;
 
+/// How the colors of overlapping events are combined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BlendMode {
+    /// Interpolate hue and saturation in rectangular coordinates, weighted by
+    /// brightness, and add the brightness.  Opposing hues desaturate.
+    Rectangular,
+    /// Interpolate hue around the shortest arc of the color wheel, weighted by
+    /// brightness, and add the brightness.  Opposing hues stay saturated.
+    ShortestArc,
+    /// Add the colors as light, in linear RGB.
+    AdditiveRgb,
+    /// Highest takes precedence: the brighter color wins.
+    Htp,
+    /// Latest takes precedence: the newer color crossfades in over the older
+    /// one, and wins outright once it has taken over.
+    Ltp,
+    /// Multiply the colors in linear RGB, so the older color filters the newer.
+    Multiply,
+}
+
+impl Default for BlendMode {
+    fn default() -> Self {
+        Self::Rectangular
+    }
+}
+
 /// A trait for a color in a particular color space.
-pub trait Color: Sized + Clone {
+pub trait Color: Sized + Clone + RgbColor {
     const BLACK: Self;
 
     fn with_envelope(&self, envelope: UnipolarFloat) -> Self;
 
+    /// Return the color with its hue rotated by the provided fraction of a turn.
+    fn with_hue_shift(&self, shift: f64) -> Self;
+
+    /// Return the color with its saturation scaled by the provided factor.
+    fn with_saturation(&self, scale: UnipolarFloat) -> Self;
+
+    /// Return the color mixed with the provided proportion of white.
+    fn with_white(&self, white: UnipolarFloat) -> Self;
+
     /// Return the color with the given envelope applied.
     fn enveloped(&self, envelope: Option<UnipolarFloat>) -> Self {
         envelope
@@ -22,29 +61,205 @@ pub trait Color: Sized + Clone {
     /// The scaling factor should be used to dim the other color, but should not
     /// impact the brightness contribution of self.
     fn weighted_interpolation(&self, other: &Self, scale_factor: UnipolarFloat) -> Self;
+
+    /// Return the brightness of this color.
+    fn brightness(&self) -> UnipolarFloat;
+
+    /// Perform a weighted interpolation with other color, interpolating hue
+    /// around the shortest arc of the color wheel.
+    /// The scaling factor is used as in weighted_interpolation.
+    fn shortest_arc_interpolation(&self, other: &Self, scale_factor: UnipolarFloat) -> Self;
+
+    /// Combine this color with an older color using the provided blend mode.
+    /// The scaling factor is used to dim the other color, as in
+    /// weighted_interpolation, except for LTP where it is the crossfade weight.
+    fn blend(&self, other: &Self, scale_factor: UnipolarFloat, mode: BlendMode) -> Self {
+        match mode {
+            BlendMode::Rectangular => self.weighted_interpolation(other, scale_factor),
+            BlendMode::ShortestArc => self.shortest_arc_interpolation(other, scale_factor),
+            BlendMode::AdditiveRgb => {
+                let (a, b) = (self.linear_rgb(), other.linear_rgb());
+                let s = scale_factor.val();
+                let sum = [a[0] + s * b[0], a[1] + s * b[1], a[2] + s * b[2]];
+                // Scale sums brighter than full down together, to keep their hue.
+                let max = sum[0].max(sum[1]).max(sum[2]).max(1.);
+                Self::from_linear_rgb([sum[0] / max, sum[1] / max, sum[2] / max])
+            }
+            BlendMode::Htp => {
+                if other.brightness() * scale_factor > self.brightness() {
+                    other.with_envelope(scale_factor)
+                } else {
+                    self.clone()
+                }
+            }
+            BlendMode::Ltp => {
+                let (a, b) = (self.linear_rgb(), other.linear_rgb());
+                let s = scale_factor.val();
+                Self::from_linear_rgb([
+                    lerp(b[0], a[0], s),
+                    lerp(b[1], a[1], s),
+                    lerp(b[2], a[2], s),
+                ])
+            }
+            BlendMode::Multiply => {
+                let (a, b) = (self.linear_rgb(), other.linear_rgb());
+                let s = scale_factor.val();
+                Self::from_linear_rgb([a[0] * s * b[0], a[1] * s * b[1], a[2] * s * b[2]])
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// A color in the HSV space.
+pub struct HsvColor {
+    pub hue: Phase,
+    pub saturation: UnipolarFloat,
+    pub value: UnipolarFloat,
+}
+
+impl HsvColor {
+    pub fn new(hue: Phase, saturation: UnipolarFloat, value: UnipolarFloat) -> Self {
+        Self {
+            hue,
+            saturation,
+            value,
+        }
+    }
+
+    /// Get the hue/saturation component in rectangular coordinates.
+    fn rect(&self) -> (f64, f64) {
+        let x = self.saturation.val() * (TWOPI * self.hue.val()).cos();
+        let y = self.saturation.val() * (TWOPI * self.hue.val()).sin();
+        (x, y)
+    }
+
+    /// Convert this color to gamma-encoded sRGB components.
+    pub fn to_rgb(&self) -> [f64; 3] {
+        let (h, s, v) = (self.hue.val() * 6., self.saturation.val(), self.value.val());
+        let c = v * s;
+        let x = c * (1. - (h % 2. - 1.).abs());
+        let m = v - c;
+        let (r, g, b) = match h as u32 {
+            0 => (c, x, 0.),
+            1 => (x, c, 0.),
+            2 => (0., c, x),
+            3 => (0., x, c),
+            4 => (x, 0., c),
+            _ => (c, 0., x),
+        };
+        [r + m, g + m, b + m]
+    }
+
+    /// Create a color from gamma-encoded sRGB components.
+    pub fn from_rgb(rgb: [f64; 3]) -> Self {
+        let [r, g, b] = rgb;
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+        let hue = if delta <= 0. {
+            0.
+        } else if max == r {
+            ((g - b) / delta) / 6.
+        } else if max == g {
+            ((b - r) / delta + 2.) / 6.
+        } else {
+            ((r - g) / delta + 4.) / 6.
+        };
+        let saturation = if max <= 0. { 0. } else { delta / max };
+        Self::new(
+            Phase::new(hue),
+            UnipolarFloat::new(saturation),
+            UnipolarFloat::new(max),
+        )
+    }
+}
+
+impl Color for HsvColor {
+    const BLACK: Self = Self {
+        hue: Phase::ZERO,
+        saturation: UnipolarFloat::ONE,
+        value: UnipolarFloat::ZERO,
+    };
+
+    fn with_envelope(&self, envelope: UnipolarFloat) -> Self {
+        let mut copy = self.clone();
+        copy.value *= envelope;
+        copy
+    }
+
+    fn with_hue_shift(&self, shift: f64) -> Self {
+        Self::new(
+            Phase::new(self.hue.val() + shift),
+            self.saturation,
+            self.value,
+        )
+    }
+
+    fn with_saturation(&self, scale: UnipolarFloat) -> Self {
+        Self::new(self.hue, self.saturation * scale, self.value)
+    }
+
+    fn with_white(&self, white: UnipolarFloat) -> Self {
+        Self::new(
+            self.hue,
+            self.saturation * (UnipolarFloat::ONE - white),
+            self.value + white * (UnipolarFloat::ONE - self.value),
+        )
+    }
+
+    fn weighted_interpolation(&self, other: &Self, scale_factor: UnipolarFloat) -> Self {
+        let value = other.value * scale_factor + self.value;
+        if value == UnipolarFloat::ZERO {
+            return Self::BLACK;
+        }
+
+        // interpolate shade in rectangular coordinates
+        let alpha = self.value.val() / value.val();
+        let ((x_self, y_self), (x_other, y_other)) = (self.rect(), other.rect());
+        let x = lerp(x_other, x_self, alpha);
+        let y = lerp(y_other, y_self, alpha);
+        let (saturation, hue) = polar(x, y);
+        Self {
+            hue,
+            saturation: UnipolarFloat::new(saturation),
+            value,
+        }
+    }
+
+    fn brightness(&self) -> UnipolarFloat {
+        self.value
+    }
+
+    fn shortest_arc_interpolation(&self, other: &Self, scale_factor: UnipolarFloat) -> Self {
+        let value = other.value * scale_factor + self.value;
+        if value == UnipolarFloat::ZERO {
+            return Self::BLACK;
+        }
+        let alpha = self.value.val() / value.val();
+        Self {
+            hue: arc_lerp(other.hue, self.hue, alpha),
+            saturation: UnipolarFloat::new(lerp(
+                other.saturation.val(),
+                self.saturation.val(),
+                alpha,
+            )),
+            value,
+        }
+    }
+}
+
+impl From<HsluvColor> for HsvColor {
+    fn from(color: HsluvColor) -> Self {
+        Self::from_rgb(hsluv::hsluv_to_rgb(color.components()))
+    }
 }
 
-// #[derive(Clone)]
-// /// A color in the HSV space.
-// pub struct HsvColor {
-//     pub hue: Phase,
-//     pub saturation: UnipolarFloat,
-//     pub value: UnipolarFloat,
-// }
-
-// impl Color for HsvColor {
-//     const BLACK: Self = Self {
-//         hue: Phase::ZERO,
-//         saturation: UnipolarFloat::ONE,
-//         value: UnipolarFloat::ZERO,
-//     };
-
-//     fn with_envelope(&self, envelope: UnipolarFloat) -> Self {
-//         let mut copy = self.clone();
-//         copy.value *= envelope;
-//         copy
-//     }
-// }
+impl From<HsvColor> for HsluvColor {
+    fn from(color: HsvColor) -> Self {
+        Self::from_components(hsluv::rgb_to_hsluv(color.to_rgb()))
+    }
+}
 
 #[derive(Clone)]
 /// A color in the HSLuv space.
@@ -66,6 +281,26 @@ impl HsluvColor {
         }
     }
 
+    /// Return the hue in degrees and the saturation and lightness as
+    /// percentages, as used by the HSLuv conversions.
+    pub(crate) fn components(&self) -> [f64; 3] {
+        [
+            self.hue.val() * 360.,
+            self.saturation.val() * 100.,
+            self.lightness.val() * 100.,
+        ]
+    }
+
+    /// Create a color from a hue in degrees and the saturation and lightness
+    /// as percentages.
+    pub(crate) fn from_components([h, s, l]: [f64; 3]) -> Self {
+        Self::new(
+            Phase::new(h / 360.),
+            UnipolarFloat::new(s / 100.),
+            UnipolarFloat::new(l / 100.),
+        )
+    }
+
     /// Get memoized rectangular coordinates.
     fn rect(&self) -> (f64, f64) {
         self.rect.get().unwrap_or_else(|| {
@@ -91,6 +326,26 @@ impl Color for HsluvColor {
         copy
     }
 
+    fn with_hue_shift(&self, shift: f64) -> Self {
+        Self::new(
+            Phase::new(self.hue.val() + shift),
+            self.saturation,
+            self.lightness,
+        )
+    }
+
+    fn with_saturation(&self, scale: UnipolarFloat) -> Self {
+        Self::new(self.hue, self.saturation * scale, self.lightness)
+    }
+
+    fn with_white(&self, white: UnipolarFloat) -> Self {
+        Self::new(
+            self.hue,
+            self.saturation * (UnipolarFloat::ONE - white),
+            self.lightness + white * (UnipolarFloat::ONE - self.lightness),
+        )
+    }
+
     fn weighted_interpolation(&self, other: &Self, scale_factor: UnipolarFloat) -> Self {
         let lightness = other.lightness * scale_factor + self.lightness;
 
@@ -107,16 +362,202 @@ impl Color for HsluvColor {
             rect: Cell::new(Some((x, y))),
         }
     }
+
+    fn brightness(&self) -> UnipolarFloat {
+        self.lightness
+    }
+
+    fn shortest_arc_interpolation(&self, other: &Self, scale_factor: UnipolarFloat) -> Self {
+        let lightness = other.lightness * scale_factor + self.lightness;
+        if lightness == UnipolarFloat::ZERO {
+            return Self::BLACK;
+        }
+        let alpha = self.lightness.val() / lightness.val();
+        Self::new(
+            arc_lerp(other.hue, self.hue, alpha),
+            UnipolarFloat::new(lerp(other.saturation.val(), self.saturation.val(), alpha)),
+            lightness,
+        )
+    }
 }
 
 /// Convert rectangular coordinates into polar coordinates.
 fn polar(x: f64, y: f64) -> (f64, Phase) {
     let r = (x.powi(2) + y.powi(2)).sqrt();
-    let theta = Phase::new((y / x).atan() / TWOPI);
+    let theta = Phase::new(y.atan2(x) / TWOPI);
     (r, theta)
 }
 
 /// Linear interpolation.
-fn lerp(v_old: f64, v_new: f64, alpha: f64) -> f64 {
+pub(crate) fn lerp(v_old: f64, v_new: f64, alpha: f64) -> f64 {
     alpha * v_new + (1. - alpha) * v_old
 }
+
+/// Interpolate between two hues around the shortest arc between them.
+fn arc_lerp(h_old: Phase, h_new: Phase, alpha: f64) -> Phase {
+    let mut delta = h_new.val() - h_old.val();
+    if delta > 0.5 {
+        delta -= 1.;
+    } else if delta < -0.5 {
+        delta += 1.;
+    }
+    Phase::new(h_old.val() + alpha * delta)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hsv(hue: f64, saturation: f64, value: f64) -> HsvColor {
+        HsvColor::new(
+            Phase::new(hue),
+            UnipolarFloat::new(saturation),
+            UnipolarFloat::new(value),
+        )
+    }
+
+    fn assert_close(expected: f64, actual: f64) {
+        assert!(
+            (expected - actual).abs() < 1e-6,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_hsv_rgb() {
+        assert_eq!([1., 0., 0.], hsv(0., 1., 1.).to_rgb());
+        assert_eq!([0., 0.5, 0.5], hsv(0.5, 1., 0.5).to_rgb());
+        assert_eq!([0.25, 0.25, 0.25], hsv(0.7, 0., 0.25).to_rgb());
+        let color = HsvColor::from_rgb([0.2, 0.4, 0.8]);
+        let [r, g, b] = color.to_rgb();
+        assert_close(0.2, r);
+        assert_close(0.4, g);
+        assert_close(0.8, b);
+    }
+
+    #[test]
+    fn test_hsluv_round_trip() {
+        for color in &[hsv(0., 1., 1.), hsv(0.3, 0.5, 0.8), hsv(0.9, 0.25, 0.4)] {
+            let round_trip = HsvColor::from(HsluvColor::from(color.clone()));
+            assert_close(color.hue.val(), round_trip.hue.val());
+            assert_close(color.saturation.val(), round_trip.saturation.val());
+            assert_close(color.value.val(), round_trip.value.val());
+        }
+        // White in HSV is white in HSLuv.
+        let white = HsluvColor::from(hsv(0.2, 0., 1.));
+        assert_close(1., white.lightness.val());
+        assert_close(0., white.saturation.val());
+    }
+
+    #[test]
+    fn test_hsv_interpolation() {
+        // Equal parts of red and green, at equal value, are a half-saturated yellow.
+        let red = hsv(0., 1., 0.5);
+        let green = hsv(1. / 3., 1., 0.5);
+        let mixed = green.weighted_interpolation(&red, UnipolarFloat::ONE);
+        assert_close(1. / 6., mixed.hue.val());
+        assert_close(0.5, mixed.saturation.val());
+        assert_eq!(UnipolarFloat::ONE, mixed.value);
+
+        // A fully dimmed other color has no influence.
+        let mixed = red.weighted_interpolation(&green, UnipolarFloat::ZERO);
+        assert_close(0., mixed.hue.val());
+        assert_close(1., mixed.saturation.val());
+
+        // Mixing black with black stays black.
+        let black = HsvColor::BLACK.weighted_interpolation(&HsvColor::BLACK, UnipolarFloat::ONE);
+        assert_eq!(UnipolarFloat::ZERO, black.value);
+    }
+
+    #[test]
+    /// Hues on the far side of the color wheel from zero interpolate correctly.
+    fn test_interpolation_quadrants() {
+        let cyan = hsv(0.5, 1., 0.5);
+        let blue = hsv(2. / 3., 1., 0.5);
+        let mixed = cyan.weighted_interpolation(&blue, UnipolarFloat::ONE);
+        assert_close(7. / 12., mixed.hue.val());
+
+        let green = hsv(1. / 3., 1., 0.5);
+        let mixed = green.weighted_interpolation(&blue, UnipolarFloat::ZERO);
+        assert_close(1. / 3., mixed.hue.val());
+
+        for hue in &[0.3, 0.5, 0.7] {
+            let color = HsluvColor::new(
+                Phase::new(*hue),
+                UnipolarFloat::ONE,
+                UnipolarFloat::new(0.5),
+            );
+            let mixed = color.weighted_interpolation(&color, UnipolarFloat::ONE);
+            assert_close(*hue, mixed.hue.val());
+        }
+    }
+
+    #[test]
+    /// Opposing hues desaturate when interpolated rectangularly, but stay
+    /// saturated around the shortest arc.
+    fn test_shortest_arc() {
+        let red = hsv(0.9, 1., 0.5);
+        let cyan = hsv(0.4, 1., 0.5);
+        let rect = red.blend(&cyan, UnipolarFloat::ONE, BlendMode::Rectangular);
+        assert_close(0., rect.saturation.val());
+        let arc = red.blend(&cyan, UnipolarFloat::ONE, BlendMode::ShortestArc);
+        assert_close(1., arc.saturation.val());
+        assert_close(0.65, arc.hue.val());
+        assert_eq!(UnipolarFloat::ONE, arc.value);
+
+        // The shortest arc may cross zero.
+        let arc = hsv(0.9, 1., 0.5).blend(
+            &hsv(0.2, 1., 0.5),
+            UnipolarFloat::ONE,
+            BlendMode::ShortestArc,
+        );
+        assert_close(0.05, arc.hue.val());
+    }
+
+    #[test]
+    fn test_precedence() {
+        let dim = hsv(0.1, 1., 0.3);
+        let bright = hsv(0.6, 1., 0.8);
+        let htp = dim.blend(&bright, UnipolarFloat::ONE, BlendMode::Htp);
+        assert_close(0.6, htp.hue.val());
+        assert_close(0.8, htp.value.val());
+        // The other color is dimmed by the scale factor before comparing.
+        let htp = dim.blend(&bright, UnipolarFloat::new(0.25), BlendMode::Htp);
+        assert_close(0.1, htp.hue.val());
+
+        let ltp = dim.blend(&bright, UnipolarFloat::ONE, BlendMode::Ltp);
+        assert_close(0.1, ltp.hue.val());
+        assert_close(0.3, ltp.value.val());
+        // Before the newer color takes over at all, the older color remains.
+        let ltp = dim.blend(&bright, UnipolarFloat::ZERO, BlendMode::Ltp);
+        assert_close(0.6, ltp.hue.val());
+        assert_close(0.8, ltp.value.val());
+    }
+
+    #[test]
+    fn test_rgb_blending() {
+        let red = HsvColor::from_linear_rgb([1., 0., 0.]);
+        let green = HsvColor::from_linear_rgb([0., 1., 0.]);
+        let yellow = red.blend(&green, UnipolarFloat::ONE, BlendMode::AdditiveRgb);
+        assert_close(1. / 6., yellow.hue.val());
+        assert_close(1., yellow.value.val());
+
+        // Sums brighter than full are scaled down together.
+        let white = HsvColor::from_linear_rgb([1., 1., 1.]);
+        let pink = red.blend(&white, UnipolarFloat::ONE, BlendMode::AdditiveRgb);
+        let [r, g, b] = pink.linear_rgb();
+        assert_close(1., r);
+        assert_close(0.5, g);
+        assert_close(0.5, b);
+
+        let filtered = white.blend(&red, UnipolarFloat::ONE, BlendMode::Multiply);
+        let [r, g, b] = filtered.linear_rgb();
+        assert_close(1., r);
+        assert_close(0., g);
+        assert_close(0., b);
+        let black = green.blend(&red, UnipolarFloat::ONE, BlendMode::Multiply);
+        assert_eq!(UnipolarFloat::ZERO, black.value);
+    }
+}
@@ -0,0 +1,494 @@
+//! Shapes for envelope transition edges.
+
+use number::UnipolarFloat;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::f64::consts::PI;
+use std::fmt;
+
+use crate::color::lerp;
+
+/// Curvature scaling for the exponential and logarithmic edges.
+/// A curvature of 1 corresponds to this exponential rate.
+const MAX_EXPONENTIAL_RATE: f64 = 8.0;
+
+/// Curvature scaling for the S-curve edge.
+/// A curvature of 1 corresponds to this logistic steepness.
+const MAX_SIGMOID_STEEPNESS: f64 = 12.0;
+
+/// Below this rate, curved edges are treated as linear to avoid dividing by
+/// numbers close to zero.
+const MIN_RATE: f64 = 1e-6;
+
+/// Custom curve endpoints may differ from their ideal values by this much, to
+/// allow for rounding in curves loaded from show files.
+const ENDPOINT_TOLERANCE: f64 = 1e-6;
+
+/// The number of bisection steps used to invert the x coordinate of a Bezier
+/// segment, which resolves the curve parameter to within 2^-48.
+const BEZIER_SOLVER_STEPS: usize = 48;
+
+/// The shape of an envelope transition edge.
+/// EdgeShapes should always map 0 to 0 and 1 to 1, but may provide any other
+/// profile.  EdgeShapes should define a rising edge; the domain will be reversed
+/// to create falling edges.
+///
+/// Curvature parameters are unipolar; a curvature of 0 is always equivalent to
+/// a linear edge.
+///
+/// Custom curves are validated against this contract when they are created or
+/// deserialized.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum EdgeShape {
+    /// A straight line.
+    Linear,
+    /// Starts slowly and accelerates towards the end of the edge.
+    Exponential { curvature: UnipolarFloat },
+    /// Starts quickly and slows towards the end of the edge.
+    /// This is the inverse of the exponential edge.
+    Logarithmic { curvature: UnipolarFloat },
+    /// Slow at both ends and fast in the middle, following a logistic curve.
+    SCurve { curvature: UnipolarFloat },
+    /// Half of a cosine cycle, crossfaded with a linear edge by curvature.
+    Cosine { curvature: UnipolarFloat },
+    /// A square-law edge, crossfaded with a linear edge by curvature.
+    SquareLaw { curvature: UnipolarFloat },
+    /// A staircase with the provided number of equal steps.
+    /// Zero steps is treated as a linear edge.
+    Stepped { steps: u32 },
+    /// A user-defined curve made of cubic Bezier segments.
+    Bezier(BezierCurve),
+    /// A user-defined curve sampled at evenly-spaced positions.
+    Table(LookupTable),
+}
+
+impl Default for EdgeShape {
+    fn default() -> Self {
+        Self::Linear
+    }
+}
+
+impl EdgeShape {
+    /// Return the value of this edge at the provided position along it.
+    pub fn apply(&self, alpha: UnipolarFloat) -> UnipolarFloat {
+        // Pin the endpoints exactly, regardless of floating point error.
+        if alpha == UnipolarFloat::ZERO || alpha == UnipolarFloat::ONE {
+            return alpha;
+        }
+        let x = alpha.val();
+        use EdgeShape::*;
+        UnipolarFloat::new(match *self {
+            Linear => x,
+            Exponential { curvature } => {
+                let k = curvature.val() * MAX_EXPONENTIAL_RATE;
+                if k < MIN_RATE {
+                    x
+                } else {
+                    (k * x).exp_m1() / k.exp_m1()
+                }
+            }
+            Logarithmic { curvature } => {
+                let k = curvature.val() * MAX_EXPONENTIAL_RATE;
+                if k < MIN_RATE {
+                    x
+                } else {
+                    (x * k.exp_m1()).ln_1p() / k
+                }
+            }
+            SCurve { curvature } => {
+                let k = curvature.val() * MAX_SIGMOID_STEEPNESS;
+                if k < MIN_RATE {
+                    x
+                } else {
+                    let low = sigmoid(-k / 2.);
+                    let high = sigmoid(k / 2.);
+                    (sigmoid(k * (x - 0.5)) - low) / (high - low)
+                }
+            }
+            Cosine { curvature } => lerp(x, (1. - (PI * x).cos()) / 2., curvature.val()),
+            SquareLaw { curvature } => lerp(x, x * x, curvature.val()),
+            Stepped { steps } => {
+                if steps == 0 {
+                    x
+                } else {
+                    let steps = steps as f64;
+                    (x * steps).floor() / steps
+                }
+            }
+            Bezier(ref curve) => curve.value(x),
+            Table(ref table) => table.value(x),
+        })
+    }
+
+    /// Return the value of a transition between two levels using this edge.
+    /// Falling transitions run the edge backwards.
+    pub fn transition(
+        &self,
+        from: UnipolarFloat,
+        to: UnipolarFloat,
+        alpha: UnipolarFloat,
+    ) -> UnipolarFloat {
+        if to >= from {
+            from + self.apply(alpha) * (to - from)
+        } else {
+            to + self.apply(UnipolarFloat::ONE - alpha) * (from - to)
+        }
+    }
+}
+
+/// A point on a custom curve.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Point {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl Point {
+    pub fn new(x: f64, y: f64) -> Self {
+        Self { x, y }
+    }
+}
+
+/// A rising edge made of one or more cubic Bezier segments, joined end to end.
+/// The points are the start of the first segment followed by two control
+/// points and an end point for each segment, so a curve with n segments has
+/// 3n + 1 points.
+///
+/// The curve must start at (0, 0) and end at (1, 1).  Every point must lie in
+/// the unit square, and x must not decrease from one segment to the next, with
+/// each segment's control points lying between its ends.  This guarantees
+/// there is exactly one value of the curve for every position along the edge.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<Point>", into = "Vec<Point>")]
+pub struct BezierCurve(Vec<Point>);
+
+impl BezierCurve {
+    pub fn new(points: Vec<Point>) -> Result<Self, CurveError> {
+        if points.len() < 4 || (points.len() - 1) % 3 != 0 {
+            return Err(CurveError::BezierPointCount(points.len()));
+        }
+        for (i, p) in points.iter().enumerate() {
+            if !in_unit_range(p.x) || !in_unit_range(p.y) {
+                return Err(CurveError::OutOfRange(i));
+            }
+        }
+        check_endpoints(points[0], points[points.len() - 1])?;
+        for (i, segment) in points.windows(4).step_by(3).enumerate() {
+            let (start, end) = (segment[0].x, segment[3].x);
+            if end < start || segment[1..3].iter().any(|p| p.x < start || p.x > end) {
+                return Err(CurveError::NotAFunction(i));
+            }
+        }
+        Ok(Self(points))
+    }
+
+    /// Return the points defining this curve.
+    pub fn points(&self) -> &[Point] {
+        &self.0
+    }
+
+    /// Return the value of the curve at the provided x.
+    fn value(&self, x: f64) -> f64 {
+        let segment = self
+            .0
+            .windows(4)
+            .step_by(3)
+            .find(|segment| x <= segment[3].x)
+            .unwrap_or_else(|| &self.0[self.0.len() - 4..]);
+        // Find the curve parameter for this x by bisection; x increases
+        // monotonically with the parameter inside a valid segment.
+        let (mut low, mut high) = (0., 1.);
+        for _ in 0..BEZIER_SOLVER_STEPS {
+            let mid = (low + high) / 2.;
+            if cubic(segment[0].x, segment[1].x, segment[2].x, segment[3].x, mid) < x {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        let t = (low + high) / 2.;
+        cubic(segment[0].y, segment[1].y, segment[2].y, segment[3].y, t)
+    }
+}
+
+impl TryFrom<Vec<Point>> for BezierCurve {
+    type Error = CurveError;
+
+    fn try_from(points: Vec<Point>) -> Result<Self, Self::Error> {
+        Self::new(points)
+    }
+}
+
+impl From<BezierCurve> for Vec<Point> {
+    fn from(curve: BezierCurve) -> Self {
+        curve.0
+    }
+}
+
+/// A rising edge sampled at evenly-spaced positions along it, from the start
+/// of the edge to the end.  Values between samples are linearly interpolated.
+///
+/// The table must have at least two values, all in the range [0, 1], and must
+/// start at 0 and end at 1.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<f64>", into = "Vec<f64>")]
+pub struct LookupTable(Vec<f64>);
+
+impl LookupTable {
+    pub fn new(values: Vec<f64>) -> Result<Self, CurveError> {
+        if values.len() < 2 {
+            return Err(CurveError::TableLength(values.len()));
+        }
+        if let Some(i) = values.iter().position(|v| !in_unit_range(*v)) {
+            return Err(CurveError::OutOfRange(i));
+        }
+        check_endpoints(
+            Point::new(0., values[0]),
+            Point::new(1., values[values.len() - 1]),
+        )?;
+        Ok(Self(values))
+    }
+
+    /// Return the sampled values of this table.
+    pub fn values(&self) -> &[f64] {
+        &self.0
+    }
+
+    /// Return the value of the table at the provided x.
+    fn value(&self, x: f64) -> f64 {
+        let position = x * (self.0.len() - 1) as f64;
+        let i = (position.floor() as usize).min(self.0.len() - 2);
+        lerp(self.0[i], self.0[i + 1], position - i as f64)
+    }
+}
+
+impl TryFrom<Vec<f64>> for LookupTable {
+    type Error = CurveError;
+
+    fn try_from(values: Vec<f64>) -> Result<Self, Self::Error> {
+        Self::new(values)
+    }
+}
+
+impl From<LookupTable> for Vec<f64> {
+    fn from(table: LookupTable) -> Self {
+        table.0
+    }
+}
+
+/// Reasons a custom curve may be rejected.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CurveError {
+    /// A Bezier curve must have 3n + 1 points for n segments.
+    BezierPointCount(usize),
+    /// A lookup table must have at least two values.
+    TableLength(usize),
+    /// The point or value at this index is outside the unit range.
+    OutOfRange(usize),
+    /// The curve does not start at 0.
+    Start,
+    /// The curve does not end at 1.
+    End,
+    /// This Bezier segment doubles back on itself, so it does not define a
+    /// single value for every position.
+    NotAFunction(usize),
+}
+
+impl fmt::Display for CurveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use CurveError::*;
+        match self {
+            BezierPointCount(n) => write!(
+                f,
+                "a Bezier curve needs 3n + 1 points for n segments, but has {}",
+                n
+            ),
+            TableLength(n) => write!(f, "a lookup table needs at least 2 values, but has {}", n),
+            OutOfRange(i) => write!(f, "point {} is outside the range [0, 1]", i),
+            Start => write!(f, "the curve must map 0 to 0"),
+            End => write!(f, "the curve must map 1 to 1"),
+            NotAFunction(i) => write!(f, "Bezier segment {} doubles back on itself", i),
+        }
+    }
+}
+
+impl Error for CurveError {}
+
+/// Return true if the value is a number in the range [0, 1].
+fn in_unit_range(v: f64) -> bool {
+    (0. ..=1.).contains(&v)
+}
+
+/// Check that a curve starts at (0, 0) and ends at (1, 1).
+fn check_endpoints(start: Point, end: Point) -> Result<(), CurveError> {
+    if start.x.abs() > ENDPOINT_TOLERANCE || start.y.abs() > ENDPOINT_TOLERANCE {
+        return Err(CurveError::Start);
+    }
+    if (1. - end.x).abs() > ENDPOINT_TOLERANCE || (1. - end.y).abs() > ENDPOINT_TOLERANCE {
+        return Err(CurveError::End);
+    }
+    Ok(())
+}
+
+/// Evaluate a one-dimensional cubic Bezier at parameter t.
+fn cubic(p0: f64, p1: f64, p2: f64, p3: f64, t: f64) -> f64 {
+    let u = 1. - t;
+    u * u * u * p0 + 3. * u * u * t * p1 + 3. * u * t * t * p2 + t * t * t * p3
+}
+
+/// The logistic function.
+fn sigmoid(x: f64) -> f64 {
+    1. / (1. + (-x).exp())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Return one of every shape, with strong curvature.
+    fn shapes() -> Vec<EdgeShape> {
+        let c = UnipolarFloat::ONE;
+        vec![
+            EdgeShape::Linear,
+            EdgeShape::Exponential { curvature: c },
+            EdgeShape::Logarithmic { curvature: c },
+            EdgeShape::SCurve { curvature: c },
+            EdgeShape::Cosine { curvature: c },
+            EdgeShape::SquareLaw { curvature: c },
+            EdgeShape::Stepped { steps: 4 },
+            EdgeShape::Bezier(ease()),
+            EdgeShape::Table(LookupTable::new(vec![0.0, 0.1, 0.5, 1.0]).unwrap()),
+        ]
+    }
+
+    /// Return a two-segment Bezier curve that eases in and out.
+    fn ease() -> BezierCurve {
+        BezierCurve::new(vec![
+            Point::new(0.0, 0.0),
+            Point::new(0.25, 0.0),
+            Point::new(0.5, 0.25),
+            Point::new(0.5, 0.5),
+            Point::new(0.5, 0.75),
+            Point::new(0.75, 1.0),
+            Point::new(1.0, 1.0),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    /// Every shape should map 0 to 0 and 1 to 1.
+    fn test_endpoints() {
+        for shape in shapes() {
+            assert_eq!(UnipolarFloat::ZERO, shape.apply(UnipolarFloat::ZERO));
+            assert_eq!(UnipolarFloat::ONE, shape.apply(UnipolarFloat::ONE));
+        }
+    }
+
+    #[test]
+    /// Every shape should define a non-decreasing rising edge.
+    fn test_monotonic() {
+        for shape in shapes() {
+            let mut prev = UnipolarFloat::ZERO;
+            for i in 0..=100 {
+                let v = shape.apply(UnipolarFloat::new(i as f64 / 100.));
+                assert!(v >= prev, "{:?} decreased at step {}", shape, i);
+                prev = v;
+            }
+        }
+    }
+
+    #[test]
+    /// Zero curvature should always produce a linear edge.
+    fn test_zero_curvature() {
+        let c = UnipolarFloat::ZERO;
+        let alpha = UnipolarFloat::new(0.25);
+        for shape in &[
+            EdgeShape::Exponential { curvature: c },
+            EdgeShape::Logarithmic { curvature: c },
+            EdgeShape::SCurve { curvature: c },
+            EdgeShape::Cosine { curvature: c },
+            EdgeShape::SquareLaw { curvature: c },
+        ] {
+            assert_eq!(alpha, shape.apply(alpha));
+        }
+    }
+
+    #[test]
+    fn test_curve_direction() {
+        let c = UnipolarFloat::ONE;
+        let alpha = UnipolarFloat::new(0.25);
+        assert!(EdgeShape::Exponential { curvature: c }.apply(alpha) < alpha);
+        assert!(EdgeShape::Logarithmic { curvature: c }.apply(alpha) > alpha);
+        assert!(EdgeShape::SCurve { curvature: c }.apply(alpha) < alpha);
+        assert_eq!(
+            UnipolarFloat::new(0.5),
+            EdgeShape::Stepped { steps: 2 }.apply(UnipolarFloat::new(0.75))
+        );
+    }
+
+    #[test]
+    fn test_bezier() {
+        let curve = ease();
+        assert!((curve.value(0.5) - 0.5).abs() < 1e-6);
+        assert!(curve.value(0.25) < 0.25);
+        assert!(curve.value(0.75) > 0.75);
+        // Control points on the diagonal produce a straight line.
+        let line = BezierCurve::new(vec![
+            Point::new(0.0, 0.0),
+            Point::new(0.25, 0.25),
+            Point::new(0.75, 0.75),
+            Point::new(1.0, 1.0),
+        ])
+        .unwrap();
+        assert!((line.value(0.3) - 0.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_table() {
+        let table = LookupTable::new(vec![0.0, 0.1, 0.5, 1.0]).unwrap();
+        assert!((table.value(0.5) - 0.3).abs() < 1e-9);
+        assert_eq!(0.5, table.value(2. / 3.));
+    }
+
+    #[test]
+    /// Custom curves that break the edge contract should be rejected.
+    fn test_validation() {
+        let p = Point::new;
+        assert_eq!(
+            Err(CurveError::BezierPointCount(3)),
+            BezierCurve::new(vec![p(0., 0.), p(0.5, 0.5), p(1., 1.)])
+        );
+        assert_eq!(
+            Err(CurveError::Start),
+            BezierCurve::new(vec![p(0., 0.1), p(0.5, 0.5), p(0.5, 0.5), p(1., 1.)])
+        );
+        assert_eq!(
+            Err(CurveError::End),
+            BezierCurve::new(vec![p(0., 0.), p(0.5, 0.5), p(0.5, 0.5), p(1., 0.9)])
+        );
+        assert_eq!(
+            Err(CurveError::OutOfRange(1)),
+            BezierCurve::new(vec![p(0., 0.), p(0.5, 1.5), p(0.5, 0.5), p(1., 1.)])
+        );
+        assert_eq!(
+            Err(CurveError::NotAFunction(1)),
+            BezierCurve::new(vec![
+                p(0., 0.),
+                p(0.2, 0.2),
+                p(0.4, 0.4),
+                p(0.6, 0.6),
+                p(0.5, 0.7),
+                p(0.8, 0.8),
+                p(1., 1.),
+            ])
+        );
+        assert_eq!(Err(CurveError::TableLength(1)), LookupTable::new(vec![0.]));
+        assert_eq!(
+            Err(CurveError::OutOfRange(1)),
+            LookupTable::new(vec![0., f64::NAN, 1.])
+        );
+        assert_eq!(Err(CurveError::End), LookupTable::new(vec![0., 0.5]));
+        assert!(LookupTable::new(vec![0., 1. - 1e-9]).is_ok());
+    }
+}
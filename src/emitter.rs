@@ -0,0 +1,234 @@
+//! Decomposition of RGB colors into levels for fixtures with additional
+//! white, amber and UV emitters.
+
+use number::UnipolarFloat;
+use serde::{Deserialize, Serialize};
+
+use crate::rgb::Rgb;
+
+/// The linear sRGB color of an amber emitter at full output.
+const AMBER: [f64; 3] = [1., 0.52, 0.];
+
+/// The emitters present in a fixture, in the order of its channels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EmitterLayout {
+    Rgb,
+    Rgbw,
+    Rgba,
+    Rgbaw,
+    RgbawUv,
+}
+
+impl EmitterLayout {
+    fn has_white(&self) -> bool {
+        matches!(self, Self::Rgbw | Self::Rgbaw | Self::RgbawUv)
+    }
+
+    fn has_amber(&self) -> bool {
+        matches!(self, Self::Rgba | Self::Rgbaw | Self::RgbawUv)
+    }
+
+    fn has_uv(&self) -> bool {
+        matches!(self, Self::RgbawUv)
+    }
+}
+
+/// How much of a color is moved onto the white emitter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WhiteStrategy {
+    /// Move as much of the color as possible onto the white emitter.
+    /// This is the brightest, but the broad spectrum of white LEDs can wash
+    /// out saturated colors.
+    MaxWhite,
+    /// Use the white emitter in proportion to how desaturated the color is,
+    /// so saturated colors are rendered only by the colored emitters.
+    PreserveSaturation,
+}
+
+/// The level of each emitter in a fixture.
+/// Levels are linear drive levels, not gamma-encoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EmitterLevels {
+    pub red: UnipolarFloat,
+    pub green: UnipolarFloat,
+    pub blue: UnipolarFloat,
+    pub white: UnipolarFloat,
+    pub amber: UnipolarFloat,
+    pub uv: UnipolarFloat,
+}
+
+impl EmitterLevels {
+    /// Return the levels of the emitters in the provided layout, in channel order.
+    pub fn channels(&self, layout: EmitterLayout) -> Vec<UnipolarFloat> {
+        let mut channels = vec![self.red, self.green, self.blue];
+        if layout.has_amber() {
+            channels.push(self.amber);
+        }
+        if layout.has_white() {
+            channels.push(self.white);
+        }
+        if layout.has_uv() {
+            channels.push(self.uv);
+        }
+        channels
+    }
+}
+
+/// Decompose colors into emitter levels for a particular kind of fixture.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Emitters {
+    layout: EmitterLayout,
+    strategy: WhiteStrategy,
+    /// The level of the UV emitter at full brightness.
+    /// UV has no sRGB component, so it follows the brightness of the color.
+    uv: UnipolarFloat,
+}
+
+impl Emitters {
+    pub fn new(layout: EmitterLayout, strategy: WhiteStrategy) -> Self {
+        Self {
+            layout,
+            strategy,
+            uv: UnipolarFloat::ZERO,
+        }
+    }
+
+    /// Set the level of the UV emitter at full brightness.
+    pub fn with_uv(mut self, uv: UnipolarFloat) -> Self {
+        self.uv = uv;
+        self
+    }
+
+    pub fn layout(&self) -> EmitterLayout {
+        self.layout
+    }
+
+    /// Return the emitter levels that render the provided color.
+    pub fn levels(&self, color: &Rgb) -> EmitterLevels {
+        self.decompose(color.linear())
+    }
+
+    /// Return the emitter levels that render the provided linear red, green
+    /// and blue levels.
+    pub fn decompose(&self, linear: [f64; 3]) -> EmitterLevels {
+        let [mut r, mut g, mut b] = linear;
+        let max = r.max(g).max(b);
+
+        let mut white = 0.;
+        if self.layout.has_white() && max > 0. {
+            let min = r.min(g).min(b);
+            white = match self.strategy {
+                WhiteStrategy::MaxWhite => min,
+                // Scale by 1 - saturation, where saturation is 1 - min/max.
+                WhiteStrategy::PreserveSaturation => min * min / max,
+            };
+            r -= white;
+            g -= white;
+            b -= white;
+        }
+
+        // Amber is itself a saturated color, so take as much as the remaining
+        // red and green allow.
+        let mut amber = 0.;
+        if self.layout.has_amber() {
+            amber = (r / AMBER[0]).min(g / AMBER[1]).max(0.);
+            r -= amber * AMBER[0];
+            g -= amber * AMBER[1];
+            b -= amber * AMBER[2];
+        }
+
+        let uv = if self.layout.has_uv() {
+            self.uv.val() * max
+        } else {
+            0.
+        };
+
+        EmitterLevels {
+            red: UnipolarFloat::new(r),
+            green: UnipolarFloat::new(g),
+            blue: UnipolarFloat::new(b),
+            white: UnipolarFloat::new(white),
+            amber: UnipolarFloat::new(amber),
+            uv: UnipolarFloat::new(uv),
+        }
+    }
+
+    /// Return the channel levels that render the provided color, in the
+    /// channel order of this fixture's layout.
+    pub fn channels(&self, color: &Rgb) -> Vec<UnipolarFloat> {
+        self.levels(color).channels(self.layout)
+    }
+}
+
+impl Default for Emitters {
+    fn default() -> Self {
+        Self::new(EmitterLayout::Rgb, WhiteStrategy::MaxWhite)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hsluv;
+
+    /// Create a color from linear components.
+    fn linear(r: f64, g: f64, b: f64) -> Rgb {
+        Rgb::new(
+            hsluv::from_linear(r),
+            hsluv::from_linear(g),
+            hsluv::from_linear(b),
+        )
+    }
+
+    fn assert_levels(expected: &[f64], actual: Vec<UnipolarFloat>) {
+        assert_eq!(expected.len(), actual.len());
+        for (e, a) in expected.iter().zip(actual.iter()) {
+            assert!(
+                (e - a.val()).abs() < 1e-6,
+                "expected {:?}, got {:?}",
+                expected,
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn test_white() {
+        let pastel = linear(1., 0.5, 0.5);
+        let max = Emitters::new(EmitterLayout::Rgbw, WhiteStrategy::MaxWhite);
+        assert_levels(&[0.5, 0., 0., 0.5], max.channels(&pastel));
+        let preserve = Emitters::new(EmitterLayout::Rgbw, WhiteStrategy::PreserveSaturation);
+        assert_levels(&[0.75, 0.25, 0.25, 0.25], preserve.channels(&pastel));
+
+        // Both strategies render white entirely with the white emitter, and
+        // saturated colors entirely without it.
+        for emitters in &[max, preserve] {
+            assert_levels(&[0., 0., 0., 1.], emitters.channels(&linear(1., 1., 1.)));
+            assert_levels(&[0., 0.2, 1., 0.], emitters.channels(&linear(0., 0.2, 1.)));
+        }
+    }
+
+    #[test]
+    fn test_amber() {
+        let emitters = Emitters::new(EmitterLayout::Rgba, WhiteStrategy::MaxWhite);
+        assert_levels(&[0., 0., 0., 1.], emitters.channels(&linear(1., 0.52, 0.)));
+        assert_levels(
+            &[0.5, 0., 0., 0.5],
+            emitters.channels(&linear(1., 0.26, 0.)),
+        );
+        // No amber without both red and green.
+        assert_levels(&[0., 1., 0.5, 0.], emitters.channels(&linear(0., 1., 0.5)));
+    }
+
+    #[test]
+    fn test_rgbaw_uv() {
+        let emitters = Emitters::new(EmitterLayout::RgbawUv, WhiteStrategy::MaxWhite)
+            .with_uv(UnipolarFloat::new(0.5));
+        // White is taken first, then amber from what is left.
+        assert_levels(
+            &[0., 0., 0., 0.5, 0.5, 0.5],
+            emitters.channels(&linear(1., 0.76, 0.5)),
+        );
+        assert_levels(&[0.; 6], emitters.channels(&Rgb::BLACK));
+    }
+}
@@ -1,36 +1,55 @@
 use derive_more::Display;
 use log::error;
 use number::UnipolarFloat;
+use serde::{Deserialize, Serialize};
 use std::time::{Duration, Instant};
 
-/// A function defining the shape of an envelope transition edge.
-/// EdgeShapes should always map 0 to 0 and 1 to 1, but may provide any other
-/// profile.  EdgeShapes should define a rising edge; the domain will be reversed
-/// to create falling edges.
-type EdgeShape = fn(UnipolarFloat) -> UnipolarFloat;
-
-/// A linear edge function.
-pub fn linear_edge(alpha: UnipolarFloat) -> UnipolarFloat {
-    alpha
-}
+use crate::breakpoint::Breakpoints;
+use crate::edge::EdgeShape;
 
 /// The parameters of an ADSR envelope.
+/// These are a preset for a breakpoint envelope; see Breakpoints.
 /// TODO: do we want to store these parameters as durations, or as fractions of
 /// a time scale?
-#[derive(Clone)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct EnvelopeParameters {
+    /// Time between note on and the start of the attack.
+    #[serde(default)]
+    pub delay: Duration,
     pub attack: Duration,
     pub attack_level: UnipolarFloat,
     pub attack_shape: EdgeShape,
+    /// Time to hold at full level after the attack, before the decay.
+    #[serde(default)]
+    pub hold: Duration,
     pub decay: Duration,
     pub decay_shape: EdgeShape,
     pub sustain_level: UnipolarFloat,
     pub release: Duration,
     pub release_shape: EdgeShape,
+    #[serde(default)]
+    pub release_policy: ReleasePolicy,
+    /// The minimum time after the delay before the release may begin, so that
+    /// very short notes are still visible.
+    #[serde(default)]
+    pub min_gate: Duration,
+    /// If provided, loop a portion of the attack, hold and decay while held.
+    #[serde(default)]
+    pub looping: Option<Loop>,
+    /// The level reached at the end of the attack.
+    /// All other levels of the envelope are scaled by this level.
+    #[serde(default = "full_peak")]
+    pub peak: UnipolarFloat,
+}
+
+/// Envelopes saved without a peak reach full level.
+fn full_peak() -> UnipolarFloat {
+    UnipolarFloat::ONE
 }
 
 impl EnvelopeParameters {
-    /// Return envelope parameters with linear edges.
+    /// Return envelope parameters with linear edges and no delay or hold.
+    /// The envelope completes attack and decay before releasing.
     pub fn linear(
         attack: Duration,
         attack_level: UnipolarFloat,
@@ -39,38 +58,124 @@ impl EnvelopeParameters {
         release: Duration,
     ) -> Self {
         Self {
+            delay: Duration::from_secs(0),
             attack,
             attack_level,
-            attack_shape: linear_edge,
+            attack_shape: EdgeShape::Linear,
+            hold: Duration::from_secs(0),
             decay,
-            decay_shape: linear_edge,
+            decay_shape: EdgeShape::Linear,
             sustain_level,
             release,
-            release_shape: linear_edge,
+            release_shape: EdgeShape::Linear,
+            release_policy: ReleasePolicy::AfterDecay,
+            min_gate: Duration::from_secs(0),
+            looping: None,
+            peak: UnipolarFloat::ONE,
         }
     }
 }
 
-/// An evolving ADSR envelope.
-/// The current envelope value is computed during update and stored.
+/// Determines when the release ramp begins if an envelope is released before
+/// it has reached the sustain level.  The release ramp always begins from the
+/// value of the envelope at the moment the ramp starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReleasePolicy {
+    /// Begin releasing immediately from the current value.
+    Immediate,
+    /// Complete the attack, then release from the peak.
+    AfterAttack,
+    /// Complete the attack and decay, then release from the sustain level.
+    AfterDecay,
+}
+
+impl Default for ReleasePolicy {
+    /// The behavior of a classic ADSR envelope.
+    fn default() -> Self {
+        Self::AfterDecay
+    }
+}
+
+/// Repeat a region of an envelope before its sustain point while it is held,
+/// instead of proceeding to the sustain level.
+/// Once released, the envelope stops looping and continues forwards from
+/// wherever it was in the loop.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Loop {
+    /// The start of the looped region, as a fraction of the time to the sustain point.
+    pub start: UnipolarFloat,
+    /// The end of the looped region, as a fraction of the time to the sustain point.
+    /// If the end is not after the start, the envelope does not loop.
+    pub end: UnipolarFloat,
+    /// The number of times to repeat the looped region before continuing on
+    /// to the sustain level.  If None, repeat for as long as the envelope is held.
+    pub count: Option<u32>,
+    pub mode: LoopMode,
+}
+
+/// How an envelope returns to the start of a looped region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LoopMode {
+    /// Jump back to the start of the loop.
+    Restart,
+    /// Run backwards to the start of the loop, then forwards again.
+    /// One repetition is a complete round trip.
+    PingPong,
+}
+
+/// The phases an envelope passes through during its lifetime.
+/// Segments before the sustain point are classified by their direction:
+/// rising segments are attacks, flat segments are holds and falling segments
+/// are decays.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnvelopePhase {
+    Delay,
+    Attack,
+    Hold,
+    Decay,
+    Sustain,
+    Release,
+    Closed,
+}
+
+/// The region of a looping envelope, in seconds since the end of the delay.
+struct LoopRegion {
+    start: f64,
+    end: f64,
+    length: f64,
+    /// The time taken by one repetition.
+    cycle: f64,
+    count: Option<u32>,
+    mode: LoopMode,
+}
+
+/// An evolving envelope.
+/// The value of an envelope is a pure function of the time since note on and
+/// the time at which it was released, so it can be evaluated at any time
+/// using value_at, or moved to any time using seek.  The current envelope
+/// value is computed during update and stored.
 pub struct Envelope {
-    parameters: EnvelopeParameters,
+    breakpoints: Breakpoints,
+    /// Time since note on, including any delay.
     elapsed: Duration,
-    released: bool,
+    /// The time since the end of the delay at which this envelope was
+    /// released, if it has been.
+    released_at: Option<Duration>,
+    /// The time since note on at which this envelope was cut off, if it was.
+    cut_at: Option<Duration>,
     /// The current value of the envelope. Updated during state update.
     /// If None, the envelope has closed.
     value: Option<UnipolarFloat>,
-    release_elapsed: Duration,
 }
 
 impl Envelope {
-    pub fn new(parameters: EnvelopeParameters) -> Self {
+    pub fn new<B: Into<Breakpoints>>(breakpoints: B) -> Self {
         let mut envelope = Self {
             value: None,
-            parameters,
+            breakpoints: breakpoints.into(),
             elapsed: Duration::from_secs(0),
-            released: false,
-            release_elapsed: Duration::from_secs(0),
+            released_at: None,
+            cut_at: None,
         };
         // Initialize value.
         envelope.update_value();
@@ -79,12 +184,45 @@ impl Envelope {
 
     /// Return true if this envelope is released.
     pub fn released(&self) -> bool {
-        self.released
+        self.released_at.is_some()
     }
 
     /// Set this envelope as released.
+    /// Releasing an envelope that is already released has no effect.
     pub fn release(&mut self) {
-        self.released = true;
+        if self.released_at.is_none() {
+            self.released_at = Some(self.time());
+        }
+    }
+
+    /// Close this envelope immediately, without a release.
+    pub fn cut(&mut self) {
+        if self.cut_at.is_none() {
+            self.cut_at = Some(self.elapsed);
+        }
+        self.update_value();
+    }
+
+    /// Restart this envelope with new breakpoints, starting from its current
+    /// value rather than the start level of the breakpoints.
+    /// Retriggered envelopes start immediately, ignoring any delay.
+    pub fn retrigger<B: Into<Breakpoints>>(&mut self, breakpoints: B) {
+        let mut breakpoints = breakpoints.into();
+        breakpoints.start_level = self.value.unwrap_or(UnipolarFloat::ZERO);
+        breakpoints.delay = Duration::from_secs(0);
+        *self = Self::new(breakpoints);
+    }
+
+    /// Restart this envelope with its existing breakpoints, starting from its
+    /// current value.
+    pub fn restart(&mut self) {
+        self.retrigger(self.breakpoints.clone());
+    }
+
+    /// Return true if this envelope is still waiting out its delay.
+    /// Delayed envelopes have a value of zero.
+    pub fn delayed(&self) -> bool {
+        self.elapsed < self.breakpoints.delay
     }
 
     /// Return true if this envelope has closed.
@@ -104,87 +242,269 @@ impl Envelope {
         if self.value.is_none() {
             return;
         }
-        self.elapsed += delta_t;
-        if self.released {
-            self.release_elapsed += delta_t;
-        }
+        self.seek(self.elapsed + delta_t);
+    }
+
+    /// Move this envelope to the provided time since note on, forwards or
+    /// backwards.  Seeking to before the release time shows the envelope as
+    /// it was while still held, though it remains released.
+    pub fn seek(&mut self, elapsed: Duration) {
+        self.elapsed = elapsed;
+        self.update_value();
+    }
+
+    /// Return the time since note on at which this envelope was released, if
+    /// it has been.  Releases during the delay take effect at its end.
+    pub fn release_time(&self) -> Option<Duration> {
+        self.released_at
+            .map(|released_at| self.breakpoints.delay + released_at)
+    }
+
+    /// Set the time since note on at which this envelope is released, or
+    /// None if it is held indefinitely, replacing any previous release.
+    pub fn set_release_time(&mut self, released: Option<Duration>) {
+        self.released_at = released.map(|t| t.saturating_sub(self.breakpoints.delay));
         self.update_value();
     }
+
+    /// Return the value of this envelope at the provided time since note on,
+    /// given its release time.  This does not depend on the current state of
+    /// the envelope.
+    /// Return None if the envelope has closed by that time.
+    pub fn value_at(&self, elapsed: Duration) -> Option<UnipolarFloat> {
+        if self.cut_at.map_or(false, |cut_at| elapsed >= cut_at) {
+            return None;
+        }
+        if elapsed < self.breakpoints.delay {
+            return Some(UnipolarFloat::ZERO);
+        }
+        let time = elapsed - self.breakpoints.delay;
+        match self.release_start() {
+            Some(start) if time >= start => self.release_value(start, time),
+            _ => self.held_value(time),
+        }
+    }
     /// Return true if this envelope has completed the attack.
     pub fn attack_complete(&self) -> bool {
-        self.elapsed > self.parameters.attack
+        self.time() > self.breakpoints.attack()
     }
 
-    /// Update the current stored value of this envelope.
-    /// Set None if the envelope has closed.
-    fn update_value(&mut self) {
-        self.value = if self.elapsed <= self.parameters.attack {
-            // attack portion
-            let alpha = if self.parameters.attack == Duration::from_secs(0) {
-                UnipolarFloat::ONE
-            } else {
-                UnipolarFloat::new(
-                    self.elapsed.as_secs_f64() / self.parameters.attack.as_secs_f64(),
-                )
-            };
-            Some(rising_edge(
-                self.parameters.attack_shape,
-                alpha,
-                self.parameters.attack_level,
-            ))
+    /// Return the current phase of this envelope.
+    pub fn phase(&self) -> EnvelopePhase {
+        self.phase_state().0
+    }
+
+    /// Return the progress through the current phase.
+    /// Before the sustain point, this is the progress through the current
+    /// segment.  Sustain always has no progress, and closed envelopes are
+    /// always complete.
+    pub fn phase_progress(&self) -> UnipolarFloat {
+        self.phase_state().1
+    }
+
+    /// Return the time since note on.
+    pub fn time_since_note_on(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Return the time after note on at which this envelope closes, if it can
+    /// be determined.  Held envelopes that sustain or loop indefinitely cannot
+    /// be known until they are released.
+    pub fn total_duration(&self) -> Option<Duration> {
+        let close = match self.release_start() {
+            Some(start) if self.held_value(start).is_some() => {
+                Some(start + self.breakpoints.release_duration())
+            }
+            _ => self.held_close(),
+        }
+        .map(|close| self.breakpoints.delay + close);
+        match (close, self.cut_at) {
+            (Some(close), Some(cut_at)) => Some(close.min(cut_at)),
+            (close, cut_at) => close.or(cut_at),
         }
-        // decay portion
-        else if self.elapsed <= self.parameters.attack + self.parameters.decay {
-            // if decay is 0, we take the attack branch of this function so we
-            // do not need to treat decay of 0 explicitly here.
-            let decay_elapsed = self.elapsed - self.parameters.attack;
-            let alpha = UnipolarFloat::new(
-                decay_elapsed.as_secs_f64() / self.parameters.decay.as_secs_f64(),
+    }
+
+    /// Return the time remaining until this envelope closes, if it can be
+    /// determined.
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.total_duration()
+            .map(|total| total.saturating_sub(self.elapsed))
+    }
+
+    /// Return the current phase and progress through it.
+    fn phase_state(&self) -> (EnvelopePhase, UnipolarFloat) {
+        if self.closed() {
+            return (EnvelopePhase::Closed, UnipolarFloat::ONE);
+        }
+        if self.delayed() {
+            return (
+                EnvelopePhase::Delay,
+                progress(self.elapsed, self.breakpoints.delay),
             );
-            Some(falling_edge(
-                self.parameters.decay_shape,
-                alpha,
-                self.parameters.sustain_level,
-            ))
         }
-        // attack and decay are complete, either sustain or release
+        let time = self.time();
+        match self.release_start() {
+            Some(start) if time >= start => (
+                EnvelopePhase::Release,
+                progress(time - start, self.breakpoints.release_duration()),
+            ),
+            _ => self.breakpoints.held_phase(self.position(time)),
+        }
+    }
 
-        // if sustain level is 0, the envelope has closed.
-        else if self.parameters.sustain_level == UnipolarFloat::ZERO {
-            None
+    /// Return the time since the end of the delay at which this envelope
+    /// closes before releasing, if it ever does.
+    fn held_close(&self) -> Option<Duration> {
+        let sustain_level = self.breakpoints.sustain_level();
+        if self.breakpoints.sustain.is_some() && sustain_level != UnipolarFloat::ZERO {
+            return None;
         }
-        // if not released, holding the sustain level
-        else if !self.released {
-            Some(self.parameters.sustain_level)
+        // The envelope closes once it passes the sustain point.
+        let sustain_point = self.breakpoints.sustain_point();
+        if let Some(released_at) = self.released_at {
+            // Release stops looping, so we can work forwards from the release.
+            let position = self.loop_position(released_at);
+            if position < sustain_point {
+                return Some(released_at + (sustain_point - position));
+            }
         }
-        // releasing
+        match self.loop_region() {
+            None => Some(sustain_point),
+            Some(region) => region
+                .count
+                .map(|count| sustain_point + Duration::from_secs_f64(count as f64 * region.cycle)),
+        }
+    }
 
-        // Release complete, envelope is closed.
-        else if self.release_elapsed >= self.parameters.release {
-            None
+    /// Return the time since the end of the delay.
+    fn time(&self) -> Duration {
+        self.elapsed.saturating_sub(self.breakpoints.delay)
+    }
+
+    /// Return the time since the end of the delay at which the release ramp
+    /// begins, if this envelope has been released.
+    /// The release is deferred by the release policy, and until the minimum
+    /// gate time has passed.
+    /// Envelopes without a sustain point ignore release and never start one.
+    fn release_start(&self) -> Option<Duration> {
+        self.breakpoints.sustain?;
+        let earliest = match self.breakpoints.release_policy {
+            ReleasePolicy::Immediate => Duration::from_secs(0),
+            ReleasePolicy::AfterAttack => self.breakpoints.attack(),
+            ReleasePolicy::AfterDecay => self.breakpoints.sustain_point(),
+        };
+        self.released_at.map(|released_at| {
+            // The envelope may be partway back through a loop when released,
+            // so wait until it has caught up to the earliest release point.
+            let position = self.loop_position(released_at);
+            let start = if position >= earliest {
+                released_at
+            } else {
+                released_at + (earliest - position)
+            };
+            start.max(self.breakpoints.min_gate)
+        })
+    }
+
+    /// Return the position before the sustain point at the provided time
+    /// since the end of the delay, accounting for looping and release.
+    fn position(&self, elapsed: Duration) -> Duration {
+        match self.released_at {
+            // Once released, stop looping and continue forwards.
+            Some(released_at) if elapsed > released_at => {
+                self.loop_position(released_at) + (elapsed - released_at)
+            }
+            _ => self.loop_position(elapsed),
         }
-        // Releasing
-        else {
-            let alpha = UnipolarFloat::new(
-                self.release_elapsed.as_secs_f64() / self.parameters.release.as_secs_f64(),
-            );
-            Some(
-                self.parameters.sustain_level
-                    * falling_edge(self.parameters.release_shape, alpha, UnipolarFloat::ZERO),
-            )
+    }
+
+    /// Return the looped region of this envelope, if it loops.
+    fn loop_region(&self) -> Option<LoopRegion> {
+        let looping = self.breakpoints.looping.as_ref()?;
+        let span = self.breakpoints.sustain_point().as_secs_f64();
+        let start = span * looping.start.val();
+        let end = span * looping.end.val();
+        let length = end - start;
+        if length <= 0. {
+            return None;
+        }
+        let cycle = match looping.mode {
+            LoopMode::Restart => length,
+            LoopMode::PingPong => 2. * length,
         };
+        Some(LoopRegion {
+            start,
+            end,
+            length,
+            cycle,
+            count: looping.count,
+            mode: looping.mode,
+        })
+    }
+
+    /// Return the position before the sustain point at the provided time
+    /// since the end of the delay, as if the envelope had not been released.
+    fn loop_position(&self, elapsed: Duration) -> Duration {
+        let region = match self.loop_region() {
+            Some(region) => region,
+            None => return elapsed,
+        };
+        let t = elapsed.as_secs_f64();
+        if t <= region.end {
+            return elapsed;
+        }
+        let overrun = t - region.end;
+        let repetitions = (overrun / region.cycle).floor();
+        if let Some(count) = region.count {
+            if repetitions >= count as f64 {
+                // Done looping, carry on from the end of the loop.
+                return Duration::from_secs_f64(t - count as f64 * region.cycle);
+            }
+        }
+        let phase = overrun - repetitions * region.cycle;
+        Duration::from_secs_f64(match region.mode {
+            LoopMode::Restart => region.start + phase,
+            LoopMode::PingPong => {
+                if phase <= region.length {
+                    region.end - phase
+                } else {
+                    region.start + (phase - region.length)
+                }
+            }
+        })
     }
-}
 
-/// Return the value for a rising edge.
-fn rising_edge(shape: EdgeShape, alpha: UnipolarFloat, offset: UnipolarFloat) -> UnipolarFloat {
-    offset + shape(alpha) * (UnipolarFloat::ONE - offset)
+    /// Update the current stored value of this envelope.
+    /// Set None if the envelope has closed.
+    fn update_value(&mut self) {
+        self.value = self.value_at(self.elapsed);
+    }
+
+    /// Return the value of this envelope at the provided time since the end of
+    /// the delay, ignoring the release ramp.
+    /// Return None if the envelope has closed.
+    fn held_value(&self, elapsed: Duration) -> Option<UnipolarFloat> {
+        self.breakpoints.held_value(self.position(elapsed))
+    }
+
+    /// Return the value of the release ramp at the provided time since the
+    /// end of the delay, given the time at which the ramp started.
+    /// Return None if the envelope has closed.
+    fn release_value(&self, start: Duration, elapsed: Duration) -> Option<UnipolarFloat> {
+        // The ramp starts from wherever the envelope was when it began.
+        let level = self.held_value(start)?;
+        self.breakpoints.release_value(level, elapsed - start)
+    }
 }
 
-/// Return the value for a falling edge.
-fn falling_edge(shape: EdgeShape, alpha: UnipolarFloat, offset: UnipolarFloat) -> UnipolarFloat {
-    // Create a falling edge by inverting alpha, essentially running the edge backwards.
-    rising_edge(shape, UnipolarFloat::ONE - alpha, offset)
+/// Return the progress of an elapsed time through a duration.
+/// Zero-length durations are always complete.
+pub(crate) fn progress(elapsed: Duration, duration: Duration) -> UnipolarFloat {
+    if duration == Duration::from_secs(0) {
+        UnipolarFloat::ONE
+    } else {
+        UnipolarFloat::new(elapsed.as_secs_f64() / duration.as_secs_f64())
+    }
 }
 
 #[cfg(test)]
@@ -202,6 +522,33 @@ mod test {
         )
     }
 
+    #[test]
+    /// ADSR parameters saved before delay, hold, release policy, minimum gate,
+    /// looping and peak were added load as the same ADSR envelope.
+    fn test_deserialize_adsr() {
+        let json = r#"{
+            "attack": {"secs": 1, "nanos": 0},
+            "attack_level": 0.4,
+            "attack_shape": "Linear",
+            "decay": {"secs": 1, "nanos": 0},
+            "decay_shape": "Linear",
+            "sustain_level": 0.6,
+            "release": {"secs": 1, "nanos": 0},
+            "release_shape": "Linear"
+        }"#;
+        let loaded: EnvelopeParameters = serde_json::from_str(json).unwrap();
+        let expected = params();
+        assert_eq!(expected.delay, loaded.delay);
+        assert_eq!(expected.attack, loaded.attack);
+        assert_eq!(expected.attack_level, loaded.attack_level);
+        assert_eq!(expected.hold, loaded.hold);
+        assert_eq!(expected.sustain_level, loaded.sustain_level);
+        assert_eq!(expected.release_policy, loaded.release_policy);
+        assert_eq!(expected.min_gate, loaded.min_gate);
+        assert_eq!(expected.looping, loaded.looping);
+        assert_eq!(expected.peak, loaded.peak);
+    }
+
     #[test]
     /// Basic test of envelope shape.
     fn test_full_shape() {
@@ -338,4 +685,372 @@ mod test {
         envelope.update_state(Duration::from_secs(0));
         assert_eq!(None, envelope.value());
     }
+
+    #[test]
+    /// Test that the default policy completes attack and decay before releasing.
+    fn test_release_after_decay() {
+        let params = params();
+        let mut envelope = Envelope::new(params.clone());
+        envelope.update_state(Duration::from_millis(500));
+        envelope.release();
+
+        // Attack and decay proceed as if unreleased.
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::ONE), envelope.value());
+        envelope.update_state(Duration::from_secs(1));
+        assert_eq!(Some(params.sustain_level), envelope.value());
+
+        // Then release from the sustain level.
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::new(0.3)), envelope.value());
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(None, envelope.value());
+    }
+
+    #[test]
+    /// Test releasing immediately from the middle of the attack.
+    fn test_release_immediate() {
+        let mut params = params();
+        params.release_policy = ReleasePolicy::Immediate;
+        let mut envelope = Envelope::new(params);
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::new(0.7)), envelope.value());
+
+        // The release ramp starts from the current value.
+        envelope.release();
+        envelope.update_state(Duration::from_secs(0));
+        assert_eq!(Some(UnipolarFloat::new(0.7)), envelope.value());
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::new(0.35)), envelope.value());
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(None, envelope.value());
+    }
+
+    #[test]
+    /// Test releasing from the peak after completing the attack.
+    fn test_release_after_attack() {
+        let mut params = params();
+        params.release_policy = ReleasePolicy::AfterAttack;
+        let mut envelope = Envelope::new(params);
+        envelope.update_state(Duration::from_millis(500));
+        envelope.release();
+        envelope.update_state(Duration::from_millis(250));
+        assert_eq!(Some(UnipolarFloat::new(0.85)), envelope.value());
+
+        // Attack complete, release begins from the peak.
+        envelope.update_state(Duration::from_millis(250));
+        assert_eq!(Some(UnipolarFloat::ONE), envelope.value());
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::new(0.5)), envelope.value());
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(None, envelope.value());
+    }
+
+    #[test]
+    /// Releasing after the decay is complete should behave identically under
+    /// every release policy.
+    fn test_release_policy_from_sustain() {
+        for policy in &[
+            ReleasePolicy::Immediate,
+            ReleasePolicy::AfterAttack,
+            ReleasePolicy::AfterDecay,
+        ] {
+            let mut params = params();
+            params.release_policy = *policy;
+            let mut envelope = Envelope::new(params.clone());
+            envelope.update_state(Duration::from_secs(3));
+            envelope.release();
+            envelope.update_state(Duration::from_millis(500));
+            assert_eq!(Some(UnipolarFloat::new(0.3)), envelope.value());
+        }
+    }
+
+    /// Return basic envelope parameters, looping from halfway through the
+    /// attack to halfway through the decay.
+    fn looping_params(mode: LoopMode, count: Option<u32>) -> EnvelopeParameters {
+        let mut params = params();
+        params.looping = Some(Loop {
+            start: UnipolarFloat::new(0.25),
+            end: UnipolarFloat::new(0.75),
+            count,
+            mode,
+        });
+        params
+    }
+
+    #[test]
+    fn test_loop_ping_pong() {
+        let mut envelope = Envelope::new(looping_params(LoopMode::PingPong, None));
+        envelope.update_state(Duration::from_millis(1500));
+        assert_eq!(Some(UnipolarFloat::new(0.8)), envelope.value());
+
+        // Run backwards into the attack.
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::ONE), envelope.value());
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::new(0.7)), envelope.value());
+
+        // And forwards again.
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::ONE), envelope.value());
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::new(0.8)), envelope.value());
+
+        // Should never reach the sustain level while held.
+        envelope.update_state(Duration::from_secs(1000));
+        assert_eq!(Some(UnipolarFloat::new(0.8)), envelope.value());
+    }
+
+    #[test]
+    fn test_loop_restart() {
+        let mut envelope = Envelope::new(looping_params(LoopMode::Restart, None));
+        envelope.update_state(Duration::from_millis(2000));
+        assert_eq!(Some(UnipolarFloat::ONE), envelope.value());
+        envelope.update_state(Duration::from_millis(250));
+        assert_eq!(Some(UnipolarFloat::new(0.9)), envelope.value());
+    }
+
+    #[test]
+    fn test_loop_count() {
+        let params = looping_params(LoopMode::Restart, Some(1));
+        let mut envelope = Envelope::new(params.clone());
+        envelope.update_state(Duration::from_millis(2000));
+        assert_eq!(Some(UnipolarFloat::ONE), envelope.value());
+
+        // One repetition complete, continue on to sustain.
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::new(0.8)), envelope.value());
+        envelope.update_state(Duration::from_millis(1000));
+        assert_eq!(Some(params.sustain_level), envelope.value());
+    }
+
+    #[test]
+    fn test_loop_release() {
+        // Release immediately from the middle of the loop.
+        let mut params = looping_params(LoopMode::PingPong, None);
+        params.release_policy = ReleasePolicy::Immediate;
+        let mut envelope = Envelope::new(params);
+        envelope.update_state(Duration::from_millis(2500));
+        assert_eq!(Some(UnipolarFloat::new(0.7)), envelope.value());
+        envelope.release();
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::new(0.35)), envelope.value());
+
+        // Complete attack and decay from wherever the loop was.
+        let params = looping_params(LoopMode::PingPong, None);
+        let mut envelope = Envelope::new(params.clone());
+        envelope.update_state(Duration::from_millis(2500));
+        envelope.release();
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::ONE), envelope.value());
+        envelope.update_state(Duration::from_millis(1000));
+        assert_eq!(Some(params.sustain_level), envelope.value());
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::new(0.3)), envelope.value());
+    }
+
+    #[test]
+    fn test_delay() {
+        let mut params = params();
+        params.delay = Duration::from_secs(1);
+        let mut envelope = Envelope::new(params.clone());
+        assert!(envelope.delayed());
+        assert_eq!(Some(UnipolarFloat::ZERO), envelope.value());
+
+        envelope.update_state(Duration::from_millis(500));
+        assert!(envelope.delayed());
+        assert_eq!(Some(UnipolarFloat::ZERO), envelope.value());
+
+        // Delay complete, the attack begins.
+        envelope.update_state(Duration::from_millis(500));
+        assert!(!envelope.delayed());
+        assert_eq!(Some(params.attack_level), envelope.value());
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::new(0.7)), envelope.value());
+        assert!(!envelope.attack_complete());
+    }
+
+    #[test]
+    fn test_hold() {
+        let mut params = params();
+        params.hold = Duration::from_secs(1);
+        let mut envelope = Envelope::new(params);
+        envelope.update_state(Duration::from_secs(1));
+        assert_eq!(Some(UnipolarFloat::ONE), envelope.value());
+
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::ONE), envelope.value());
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::ONE), envelope.value());
+
+        // Hold complete, decay begins.
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::new(0.8)), envelope.value());
+    }
+
+    /// Evolve an envelope and check its phase and progress.
+    fn check_phase(
+        envelope: &mut Envelope,
+        delta_t: Duration,
+        phase: EnvelopePhase,
+        progress: f64,
+    ) {
+        envelope.update_state(delta_t);
+        assert_eq!(phase, envelope.phase());
+        assert_eq!(UnipolarFloat::new(progress), envelope.phase_progress());
+    }
+
+    #[test]
+    fn test_phase() {
+        use EnvelopePhase::*;
+        let mut params = params();
+        params.delay = Duration::from_secs(1);
+        params.hold = Duration::from_secs(1);
+        let mut envelope = Envelope::new(params);
+        check_phase(&mut envelope, Duration::from_secs(0), Delay, 0.0);
+        check_phase(&mut envelope, Duration::from_millis(500), Delay, 0.5);
+        check_phase(&mut envelope, Duration::from_millis(750), Attack, 0.25);
+        check_phase(&mut envelope, Duration::from_secs(1), Hold, 0.25);
+        check_phase(&mut envelope, Duration::from_secs(1), Decay, 0.25);
+        check_phase(&mut envelope, Duration::from_secs(1), Sustain, 0.0);
+        envelope.release();
+        check_phase(&mut envelope, Duration::from_millis(250), Release, 0.25);
+        check_phase(&mut envelope, Duration::from_secs(1), Closed, 1.0);
+    }
+
+    #[test]
+    fn test_total_duration() {
+        let mut params = params();
+        params.delay = Duration::from_secs(1);
+        let mut envelope = Envelope::new(params.clone());
+        // Sustaining indefinitely, so unknown until released.
+        assert_eq!(None, envelope.total_duration());
+        envelope.update_state(Duration::from_millis(1500));
+        assert_eq!(Duration::from_millis(1500), envelope.time_since_note_on());
+        envelope.release();
+        assert_eq!(Some(Duration::from_secs(4)), envelope.total_duration());
+        assert_eq!(Some(Duration::from_millis(2500)), envelope.time_remaining());
+
+        // Envelopes with no sustain level close on their own.
+        params.sustain_level = UnipolarFloat::ZERO;
+        let envelope = Envelope::new(params.clone());
+        assert_eq!(Some(Duration::from_secs(3)), envelope.total_duration());
+
+        // As do envelopes that loop a finite number of times.
+        params.sustain_level = UnipolarFloat::ZERO;
+        params.looping = Some(Loop {
+            start: UnipolarFloat::ZERO,
+            end: UnipolarFloat::new(0.5),
+            count: Some(2),
+            mode: LoopMode::PingPong,
+        });
+        let envelope = Envelope::new(params.clone());
+        assert_eq!(Some(Duration::from_secs(7)), envelope.total_duration());
+
+        // But not those that loop indefinitely.
+        params.looping.as_mut().unwrap().count = None;
+        let mut envelope = Envelope::new(params);
+        assert_eq!(None, envelope.total_duration());
+        envelope.update_state(Duration::from_millis(2500));
+        envelope.release();
+        // Released halfway back to the start of the loop, in the attack, so
+        // the attack and decay complete before the release.
+        assert_eq!(Some(Duration::from_secs(5)), envelope.total_duration());
+    }
+
+    #[test]
+    fn test_retrigger() {
+        let params = params();
+        let mut envelope = Envelope::new(params.clone());
+        envelope.update_state(Duration::from_millis(2500));
+        envelope.release();
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::new(0.3)), envelope.value());
+
+        // Restart from the current level.
+        envelope.retrigger(params);
+        assert!(!envelope.released());
+        assert_eq!(Some(UnipolarFloat::new(0.3)), envelope.value());
+        envelope.update_state(Duration::from_secs(1));
+        assert_eq!(Some(UnipolarFloat::ONE), envelope.value());
+    }
+
+    #[test]
+    fn test_seek() {
+        let mut envelope = Envelope::new(params());
+        envelope.set_release_time(Some(Duration::from_millis(2500)));
+        assert_eq!(Some(Duration::from_millis(2500)), envelope.release_time());
+
+        // Evaluation does not depend on the current state of the envelope.
+        assert_eq!(
+            Some(UnipolarFloat::new(0.3)),
+            envelope.value_at(Duration::from_secs(3))
+        );
+        assert_eq!(
+            Some(UnipolarFloat::ONE),
+            envelope.value_at(Duration::from_secs(1))
+        );
+        assert_eq!(None, envelope.value_at(Duration::from_secs(4)));
+
+        // Seek past the close and back again.
+        envelope.seek(Duration::from_secs(4));
+        assert!(envelope.closed());
+        envelope.seek(Duration::from_secs(2));
+        assert_eq!(Some(UnipolarFloat::new(0.6)), envelope.value());
+        assert!(envelope.released());
+
+        // Seeking agrees with incremental updates.
+        let mut incremental = Envelope::new(params());
+        incremental.update_state(Duration::from_millis(2500));
+        incremental.release();
+        incremental.update_state(Duration::from_millis(500));
+        envelope.seek(Duration::from_secs(3));
+        assert_eq!(incremental.value(), envelope.value());
+        assert_eq!(incremental.phase(), envelope.phase());
+
+        // Removing the release holds the envelope at the sustain level.
+        envelope.set_release_time(None);
+        assert_eq!(Some(UnipolarFloat::new(0.6)), envelope.value());
+    }
+
+    #[test]
+    fn test_cut() {
+        let mut envelope = Envelope::new(params());
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(None, envelope.total_duration());
+        envelope.cut();
+        assert!(envelope.closed());
+        assert_eq!(Some(Duration::from_millis(500)), envelope.total_duration());
+        // The envelope was still open before it was cut.
+        assert!(envelope.value_at(Duration::from_millis(250)).is_some());
+    }
+
+    #[test]
+    fn test_min_gate() {
+        let mut params = EnvelopeParameters::linear(
+            Duration::from_secs(1),
+            UnipolarFloat::ZERO,
+            Duration::from_secs(1),
+            UnipolarFloat::ONE,
+            Duration::from_secs(1),
+        );
+        params.release_policy = ReleasePolicy::Immediate;
+        params.min_gate = Duration::from_millis(500);
+        let mut envelope = Envelope::new(params.clone());
+        envelope.update_state(Duration::from_millis(10));
+        envelope.release();
+        envelope.update_state(Duration::from_millis(490));
+        assert_eq!(Some(UnipolarFloat::new(0.5)), envelope.value());
+        assert_eq!(EnvelopePhase::Release, envelope.phase());
+        envelope.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::new(0.25)), envelope.value());
+
+        // A zero-length trigger still flashes for the minimum gate time.
+        params.attack = Duration::from_secs(0);
+        let mut envelope = Envelope::new(params);
+        envelope.release();
+        envelope.update_state(Duration::from_millis(250));
+        assert_eq!(Some(UnipolarFloat::ONE), envelope.value());
+        assert_eq!(Some(Duration::from_millis(1500)), envelope.total_duration());
+    }
 }
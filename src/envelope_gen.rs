@@ -1,55 +1,210 @@
 use std::{collections::btree_set::Union, time::Duration};
 
 use number::UnipolarFloat;
+use serde::{Deserialize, Serialize};
 
-use crate::envelope::EnvelopeParameters;
+use crate::clock::{Clock, NoteDivision};
+use crate::edge::EdgeShape;
+use crate::envelope::{EnvelopeParameters, Loop, LoopMode, ReleasePolicy};
+use crate::humanize::Humanizer;
 use crate::organ::{EmitStateChange as EmitOrganStateChange, StateChange as OrganStateChange};
+use crate::preset::EnvelopePreset;
 
 /// Generate envelope parameters based on higher-level controls.
-/// TODO: envelope shape controls, linear defaults now.
+#[derive(Clone)]
 pub struct EnvelopeGenerator {
+    delay: UnipolarFloat,
     attack: UnipolarFloat,
     attack_level: UnipolarFloat,
+    attack_shape: EdgeShape,
+    hold: UnipolarFloat,
     decay: UnipolarFloat,
+    decay_shape: EdgeShape,
     sustain_level: UnipolarFloat,
     release: UnipolarFloat,
+    release_shape: EdgeShape,
+    release_policy: ReleasePolicy,
+    /// The minimum time a note is held before its release may begin.
+    min_gate: Duration,
+    /// If true, loop the attack and decay while the envelope is held.
+    looping: bool,
+    loop_start: UnipolarFloat,
+    loop_end: UnipolarFloat,
+    loop_count: Option<u32>,
+    loop_mode: LoopMode,
+    /// The response curve applied to note velocity.
+    velocity_curve: EdgeShape,
+    /// How strongly velocity scales the level of the envelope.
+    velocity_level: UnipolarFloat,
+    /// How strongly velocity shortens the attack.  Hard hits are snappier.
+    velocity_attack: UnipolarFloat,
+    /// How strongly velocity lengthens the release.  Soft hits die away faster.
+    velocity_release: UnipolarFloat,
     /// The unit of time associated with the envelope paramters.
     /// For example, if attack is 1, it will have this length.
     time_scale: Duration,
+    /// If provided, use this note division at the current tempo as the unit
+    /// of time instead of the fixed time scale.
+    tempo_sync: Option<NoteDivision>,
+    /// Random variation applied to each generated envelope.
+    humanizer: Humanizer,
+    /// If true, every fixture a note is sent to gets its own humanized
+    /// envelope rather than sharing one.
+    humanize_per_fixture: bool,
 }
 
 impl EnvelopeGenerator {
     pub fn new() -> Self {
         Self {
+            delay: UnipolarFloat::ZERO,
             attack: UnipolarFloat::ONE,
             attack_level: UnipolarFloat::ZERO,
+            attack_shape: EdgeShape::Linear,
+            hold: UnipolarFloat::ZERO,
             decay: UnipolarFloat::ONE,
+            decay_shape: EdgeShape::Linear,
             sustain_level: UnipolarFloat::ONE,
             release: UnipolarFloat::ONE,
+            release_shape: EdgeShape::Linear,
+            release_policy: ReleasePolicy::AfterDecay,
+            min_gate: Duration::from_secs(0),
+            looping: false,
+            loop_start: UnipolarFloat::ZERO,
+            loop_end: UnipolarFloat::ONE,
+            loop_count: None,
+            loop_mode: LoopMode::Restart,
+            velocity_curve: EdgeShape::Linear,
+            velocity_level: UnipolarFloat::ZERO,
+            velocity_attack: UnipolarFloat::ZERO,
+            velocity_release: UnipolarFloat::ZERO,
             time_scale: Duration::from_secs(1),
+            tempo_sync: None,
+            humanizer: Humanizer::new(),
+            humanize_per_fixture: false,
         }
     }
 
-    /// Generate current envelope parameters.
-    pub fn generate(&self) -> EnvelopeParameters {
-        EnvelopeParameters::linear(
-            self.time_scale.mul_f64(self.attack.val()),
-            self.attack_level,
-            self.time_scale.mul_f64(self.decay.val()),
-            self.sustain_level,
-            self.time_scale.mul_f64(self.release.val()),
-        )
+    /// Generate current envelope parameters for a note with the provided velocity.
+    pub fn generate(&self, velocity: UnipolarFloat, clock: &Clock) -> EnvelopeParameters {
+        let response = self.velocity_curve.apply(velocity);
+        let time_scale = match self.tempo_sync {
+            Some(division) => clock.duration(division),
+            None => self.time_scale,
+        };
+        EnvelopeParameters {
+            delay: time_scale.mul_f64(self.delay.val()),
+            attack: time_scale
+                .mul_f64(self.attack.val() * (1. - self.velocity_attack.val() * response.val())),
+            attack_level: self.attack_level,
+            attack_shape: self.attack_shape.clone(),
+            hold: time_scale.mul_f64(self.hold.val()),
+            decay: time_scale.mul_f64(self.decay.val()),
+            decay_shape: self.decay_shape.clone(),
+            sustain_level: self.sustain_level,
+            release: time_scale
+                .mul_f64(self.release.val() * velocity_scale(response, self.velocity_release)),
+            release_shape: self.release_shape.clone(),
+            release_policy: self.release_policy,
+            min_gate: self.min_gate,
+            looping: if self.looping {
+                Some(Loop {
+                    start: self.loop_start,
+                    end: self.loop_end,
+                    count: self.loop_count,
+                    mode: self.loop_mode,
+                })
+            } else {
+                None
+            },
+            peak: UnipolarFloat::new(velocity_scale(response, self.velocity_level)),
+        }
+    }
+
+    /// Generate envelope parameters for a note with the provided velocity, with
+    /// some parameters overridden by the provided state changes.
+    /// Overrides of the humanize settings have no effect, as humanization is
+    /// applied separately.
+    pub fn generate_with_overrides(
+        &self,
+        velocity: UnipolarFloat,
+        clock: &Clock,
+        overrides: &[StateChange],
+    ) -> EnvelopeParameters {
+        if overrides.is_empty() {
+            return self.generate(velocity, clock);
+        }
+        let mut gen = self.clone();
+        for sc in overrides {
+            gen.apply(sc);
+        }
+        gen.generate(velocity, clock)
+    }
+
+    /// Return the current settings of this generator as a preset.
+    pub fn preset(&self) -> EnvelopePreset {
+        EnvelopePreset {
+            attack: self.attack,
+            decay: self.decay,
+            sustain_level: self.sustain_level,
+            release: self.release,
+            time_scale: self.time_scale,
+        }
+    }
+
+    /// Change the settings of this generator to match the provided preset.
+    pub fn apply_preset<E: EmitStateChange>(&mut self, preset: &EnvelopePreset, emitter: &mut E) {
+        use StateChange::*;
+        for sc in [
+            Attack(preset.attack),
+            Decay(preset.decay),
+            SustainLevel(preset.sustain_level),
+            Release(preset.release),
+            TimeScale(preset.time_scale),
+        ] {
+            self.handle_state_change(sc, emitter);
+        }
+    }
+
+    /// Apply random jitter to generated envelope parameters, according to the
+    /// current humanize amount.
+    pub fn humanize(&mut self, params: EnvelopeParameters) -> EnvelopeParameters {
+        self.humanizer.apply(params)
+    }
+
+    /// Return true if each fixture should receive its own humanized envelope.
+    pub fn humanize_per_fixture(&self) -> bool {
+        self.humanize_per_fixture
     }
 
     /// Emit all observable state using the provided emitter.
     pub fn emit_state<E: EmitStateChange>(&self, emitter: &mut E) {
         use StateChange::*;
+        emitter.emit_envelope_generator_state_change(Delay(self.delay));
         emitter.emit_envelope_generator_state_change(Attack(self.attack));
         emitter.emit_envelope_generator_state_change(AttackLevel(self.attack_level));
+        emitter.emit_envelope_generator_state_change(AttackShape(self.attack_shape.clone()));
+        emitter.emit_envelope_generator_state_change(Hold(self.hold));
         emitter.emit_envelope_generator_state_change(Decay(self.decay));
+        emitter.emit_envelope_generator_state_change(DecayShape(self.decay_shape.clone()));
         emitter.emit_envelope_generator_state_change(SustainLevel(self.sustain_level));
         emitter.emit_envelope_generator_state_change(Release(self.release));
+        emitter.emit_envelope_generator_state_change(ReleaseShape(self.release_shape.clone()));
+        emitter.emit_envelope_generator_state_change(ReleasePolicy(self.release_policy));
+        emitter.emit_envelope_generator_state_change(MinGate(self.min_gate));
+        emitter.emit_envelope_generator_state_change(Looping(self.looping));
+        emitter.emit_envelope_generator_state_change(LoopStart(self.loop_start));
+        emitter.emit_envelope_generator_state_change(LoopEnd(self.loop_end));
+        emitter.emit_envelope_generator_state_change(LoopCount(self.loop_count));
+        emitter.emit_envelope_generator_state_change(LoopMode(self.loop_mode));
+        emitter.emit_envelope_generator_state_change(VelocityCurve(self.velocity_curve.clone()));
+        emitter.emit_envelope_generator_state_change(VelocityLevel(self.velocity_level));
+        emitter.emit_envelope_generator_state_change(VelocityAttack(self.velocity_attack));
+        emitter.emit_envelope_generator_state_change(VelocityRelease(self.velocity_release));
         emitter.emit_envelope_generator_state_change(TimeScale(self.time_scale));
+        emitter.emit_envelope_generator_state_change(TempoSync(self.tempo_sync));
+        emitter.emit_envelope_generator_state_change(Humanize(self.humanizer.amount()));
+        emitter.emit_envelope_generator_state_change(HumanizeSeed(self.humanizer.seed()));
+        emitter.emit_envelope_generator_state_change(HumanizePerFixture(self.humanize_per_fixture));
     }
 
     /// Handle a control message.
@@ -61,16 +216,41 @@ impl EnvelopeGenerator {
     }
 
     fn handle_state_change<E: EmitStateChange>(&mut self, sc: StateChange, emitter: &mut E) {
+        self.apply(&sc);
+        emitter.emit_envelope_generator_state_change(sc);
+    }
+
+    /// Set the parameter described by the provided state change.
+    fn apply(&mut self, sc: &StateChange) {
         use StateChange::*;
-        match sc {
+        match *sc {
+            Delay(v) => self.delay = v,
             Attack(v) => self.attack = v,
             AttackLevel(v) => self.attack_level = v,
+            AttackShape(ref v) => self.attack_shape = v.clone(),
+            Hold(v) => self.hold = v,
             Decay(v) => self.decay = v,
+            DecayShape(ref v) => self.decay_shape = v.clone(),
             SustainLevel(v) => self.sustain_level = v,
             Release(v) => self.release = v,
+            ReleaseShape(ref v) => self.release_shape = v.clone(),
+            ReleasePolicy(v) => self.release_policy = v,
+            MinGate(v) => self.min_gate = v,
+            Looping(v) => self.looping = v,
+            LoopStart(v) => self.loop_start = v,
+            LoopEnd(v) => self.loop_end = v,
+            LoopCount(v) => self.loop_count = v,
+            LoopMode(v) => self.loop_mode = v,
+            VelocityCurve(ref v) => self.velocity_curve = v.clone(),
+            VelocityLevel(v) => self.velocity_level = v,
+            VelocityAttack(v) => self.velocity_attack = v,
+            VelocityRelease(v) => self.velocity_release = v,
             TimeScale(v) => self.time_scale = v,
+            TempoSync(v) => self.tempo_sync = v,
+            Humanize(v) => self.humanizer.set_amount(v),
+            HumanizeSeed(v) => self.humanizer.reseed(v),
+            HumanizePerFixture(v) => self.humanize_per_fixture = v,
         };
-        emitter.emit_envelope_generator_state_change(sc);
     }
 }
 
@@ -78,13 +258,53 @@ pub enum ControlMessage {
     Set(StateChange),
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum StateChange {
+    Delay(UnipolarFloat),
     Attack(UnipolarFloat),
     AttackLevel(UnipolarFloat),
+    AttackShape(EdgeShape),
+    Hold(UnipolarFloat),
     Decay(UnipolarFloat),
+    DecayShape(EdgeShape),
     SustainLevel(UnipolarFloat),
     Release(UnipolarFloat),
+    ReleaseShape(EdgeShape),
+    ReleasePolicy(ReleasePolicy),
+    /// The minimum time a note is held before its release may begin.
+    /// This is not affected by the time scale.
+    MinGate(Duration),
+    Looping(bool),
+    LoopStart(UnipolarFloat),
+    LoopEnd(UnipolarFloat),
+    /// Number of loop repetitions; loop for as long as held if None.
+    LoopCount(Option<u32>),
+    LoopMode(LoopMode),
+    VelocityCurve(EdgeShape),
+    /// Velocity sensitivity of the envelope level.
+    VelocityLevel(UnipolarFloat),
+    /// Velocity sensitivity of the attack time.
+    VelocityAttack(UnipolarFloat),
+    /// Velocity sensitivity of the release time.
+    VelocityRelease(UnipolarFloat),
     TimeScale(Duration),
+    /// Sync the time scale to a note division of the organ's clock.
+    /// Use the fixed time scale if None.
+    TempoSync(Option<NoteDivision>),
+    /// Amount of random jitter applied to attack, decay, release and sustain level.
+    Humanize(UnipolarFloat),
+    /// Restart the humanize random sequence from this seed.
+    HumanizeSeed(u64),
+    /// Give each fixture its own humanized envelope.
+    HumanizePerFixture(bool),
+}
+
+/// Return the factor by which to scale a parameter for the provided velocity
+/// response, given a sensitivity to velocity.
+/// Full velocity always leaves the parameter unscaled.  With full sensitivity,
+/// zero velocity scales the parameter to zero.
+fn velocity_scale(response: UnipolarFloat, sensitivity: UnipolarFloat) -> f64 {
+    1. - sensitivity.val() * (1. - response.val())
 }
 
 pub trait EmitStateChange {
@@ -96,3 +316,69 @@ impl<T: EmitOrganStateChange> EmitStateChange for T {
         self.emit_state_change(OrganStateChange::Envelope(sc));
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{
+        ControlMessage as ClockControlMessage, NoteModifier, StateChange as ClockStateChange,
+    };
+    use crate::organ::Discard;
+
+    #[test]
+    fn test_velocity_insensitive() {
+        let gen = EnvelopeGenerator::new();
+        let clock = Clock::new();
+        let soft = gen.generate(UnipolarFloat::new(0.1), &clock);
+        let hard = gen.generate(UnipolarFloat::ONE, &clock);
+        assert_eq!(UnipolarFloat::ONE, soft.peak);
+        assert_eq!(hard.attack, soft.attack);
+        assert_eq!(hard.release, soft.release);
+    }
+
+    #[test]
+    fn test_velocity_sensitive() {
+        let mut gen = EnvelopeGenerator::new();
+        for sc in [
+            StateChange::VelocityLevel(UnipolarFloat::ONE),
+            StateChange::VelocityAttack(UnipolarFloat::new(0.5)),
+            StateChange::VelocityRelease(UnipolarFloat::ONE),
+        ] {
+            gen.control(ControlMessage::Set(sc), &mut Discard);
+        }
+        let clock = Clock::new();
+        let soft = gen.generate(UnipolarFloat::new(0.25), &clock);
+        assert_eq!(UnipolarFloat::new(0.25), soft.peak);
+        assert_eq!(Duration::from_millis(875), soft.attack);
+        assert_eq!(Duration::from_millis(250), soft.release);
+
+        let hard = gen.generate(UnipolarFloat::ONE, &clock);
+        assert_eq!(UnipolarFloat::ONE, hard.peak);
+        assert_eq!(Duration::from_millis(500), hard.attack);
+        assert_eq!(Duration::from_secs(1), hard.release);
+    }
+
+    #[test]
+    fn test_tempo_sync() {
+        let mut gen = EnvelopeGenerator::new();
+        let mut clock = Clock::new();
+        gen.control(
+            ControlMessage::Set(StateChange::TempoSync(Some(NoteDivision::new(
+                1,
+                8,
+                NoteModifier::Straight,
+            )))),
+            &mut Discard,
+        );
+        let params = gen.generate(UnipolarFloat::ONE, &clock);
+        assert_eq!(Duration::from_millis(250), params.attack);
+
+        // Changing tempo changes subsequent envelopes.
+        clock.control(
+            ClockControlMessage::Set(ClockStateChange::Bpm(60.)),
+            &mut Discard,
+        );
+        let params = gen.generate(UnipolarFloat::ONE, &clock);
+        assert_eq!(Duration::from_millis(500), params.attack);
+    }
+}
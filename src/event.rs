@@ -2,13 +2,18 @@ use std::time::Duration;
 
 use number::UnipolarFloat;
 
-use crate::{color::Color, envelope::Envelope};
+use crate::{breakpoint::Breakpoints, color::Color, envelope::Envelope, modulation::Modulation};
 
 /// A color, shaped by an envelope, including envelope evolution state.
 pub struct ColorEvent<C: Color> {
     /// The color this event is initialized with.
     color: C,
     envelope: Envelope,
+    /// Modulation of the color by envelopes, other than brightness.
+    modulation: Modulation,
+    /// If present, controls how strongly this event's color takes over from
+    /// older events, instead of the brightness envelope.
+    crossfade: Option<Envelope>,
     release_id: ReleaseID,
     /// The current enveloped color.
     value: C,
@@ -19,6 +24,8 @@ impl<C: Color> ColorEvent<C> {
         let mut event = Self {
             color,
             envelope,
+            modulation: Modulation::none(),
+            crossfade: None,
             release_id,
             value: C::BLACK,
         };
@@ -26,22 +33,77 @@ impl<C: Color> ColorEvent<C> {
         event
     }
 
+    /// Apply the provided modulation to this event.
+    pub fn with_modulation(mut self, modulation: Modulation) -> Self {
+        self.modulation = modulation;
+        self.update_value();
+        self
+    }
+
+    /// Use a separate envelope to control how this event's color takes over
+    /// from older events.
+    pub fn with_crossfade(mut self, crossfade: Envelope) -> Self {
+        self.crossfade = Some(crossfade);
+        self
+    }
+
+    /// Return the release ID of this event.
+    pub fn release_id(&self) -> ReleaseID {
+        self.release_id
+    }
+
+    /// Change the color of this event without affecting its envelope.
+    pub fn set_color(&mut self, color: C) {
+        self.color = color;
+        self.update_value();
+    }
+
+    /// Restart this event with a new color and envelope.
+    /// The envelope starts from the current envelope value.
+    pub fn retrigger<B: Into<Breakpoints>>(&mut self, color: C, breakpoints: B) {
+        self.color = color;
+        self.envelope.retrigger(breakpoints);
+        self.modulation.restart();
+        if let Some(crossfade) = self.crossfade.as_mut() {
+            crossfade.restart();
+        }
+        self.update_value();
+    }
+
     /// Release the envelope in this event if the release ID matches the provided one.
     pub fn release(&mut self, release_id: ReleaseID) {
         if self.release_id == release_id {
             self.envelope.release();
+            self.modulation.release();
+            if let Some(crossfade) = self.crossfade.as_mut() {
+                crossfade.release();
+            }
         }
     }
 
+    /// Close this event immediately, without a release.
+    pub fn cut(&mut self) {
+        self.envelope.cut();
+        self.update_value();
+    }
+
     /// Update the state of this color event.
     pub fn update_state(&mut self, delta_t: Duration) {
         self.envelope.update_state(delta_t);
+        self.modulation.update_state(delta_t);
+        if let Some(crossfade) = self.crossfade.as_mut() {
+            crossfade.update_state(delta_t);
+        }
         self.update_value();
     }
 
     /// Update the current color of this event using the current envelope value.
     fn update_value(&mut self) {
-        self.value = self.color.enveloped(self.envelope.value());
+        let envelope = self.envelope.value();
+        self.value = self
+            .modulation
+            .apply(&self.color, envelope)
+            .enveloped(envelope);
     }
 
     /// Return the current value of this event.
@@ -53,6 +115,26 @@ impl<C: Color> ColorEvent<C> {
     pub fn envelope(&self) -> &Envelope {
         &self.envelope
     }
+
+    /// Return the weight with which this event's color takes over from older
+    /// events.  This is the crossfade envelope value if there is one, or the
+    /// brightness envelope value otherwise.
+    pub fn crossfade_weight(&self) -> UnipolarFloat {
+        self.crossfade
+            .as_ref()
+            .unwrap_or(&self.envelope)
+            .value()
+            .unwrap_or(UnipolarFloat::ZERO)
+    }
+
+    /// Return true if this event's color has completely taken over from older
+    /// events.
+    pub fn takeover_complete(&self) -> bool {
+        self.crossfade
+            .as_ref()
+            .unwrap_or(&self.envelope)
+            .attack_complete()
+    }
 }
 
 /// An identifier given to a color event to tie a subsequent off event to
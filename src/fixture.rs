@@ -1,9 +1,11 @@
 //! Models for fixtures that can receieve color events.
 
-use number::UnipolarFloat;
 use std::collections::VecDeque;
 
-use crate::{color::Color, store::ColorEventStrong};
+use crate::{
+    color::{BlendMode, Color},
+    store::ColorEventStrong,
+};
 
 /// A fixture that can receive color organ events.
 /// Stores a buffer of color events it is listening to, and knows how to
@@ -32,13 +34,13 @@ impl<C: Color> Fixture<C> {
         if self.event_buffer.len() == 0 {
             return;
         }
-        // If an event has completed its attack, all older events are no longer relevant.
-        // Iterate through the events, and as soon as we find one with a complete
-        // attack, discard the rest.
+        // If an event has completely taken over, all older events are no longer relevant.
+        // Iterate through the events, and as soon as we find one that has
+        // completed its takeover, discard the rest.
         if let Some(newest_complete) = self
             .event_buffer
             .iter()
-            .position(|e| e.borrow().envelope().attack_complete())
+            .position(|e| e.borrow().takeover_complete())
         {
             self.event_buffer.truncate(newest_complete + 1);
         }
@@ -49,23 +51,126 @@ impl<C: Color> Fixture<C> {
         }
     }
 
-    /// Return the current color for this fixture.
-    pub fn render(&self) -> C {
-        // Fold backwards over all events in the buffer, interpolating each pair
+    /// Return the current color for this fixture, combining overlapping
+    /// events with the provided blend mode.
+    pub fn render(&self, mode: BlendMode) -> C {
+        // Fold backwards over all events in the buffer, blending each pair
         // of color events from the oldest to the newest.
+        // Events that are still delayed have not started yet, so ignore them.
         self.event_buffer
             .iter()
             .rev()
+            .filter(|event| !event.borrow().envelope().delayed())
             .fold(None, |color_accum, event| match color_accum {
                 None => Some(event.borrow().value().clone()),
                 Some(color) => {
                     let e = event.borrow();
-                    Some(e.value().weighted_interpolation(
-                        &color,
-                        e.envelope().value().unwrap_or(UnipolarFloat::ZERO),
-                    ))
+                    Some(e.value().blend(&color, e.crossfade_weight(), mode))
                 }
             })
             .unwrap_or(C::BLACK)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use number::{Phase, UnipolarFloat};
+    use std::{cell::RefCell, rc::Rc, time::Duration};
+
+    use super::*;
+    use crate::{
+        color::HsluvColor,
+        envelope::{Envelope, EnvelopeParameters},
+        event::ColorEvent,
+        rgb::RgbColor,
+    };
+
+    fn envelope(attack: Duration) -> Envelope {
+        Envelope::new(EnvelopeParameters::linear(
+            attack,
+            UnipolarFloat::ZERO,
+            Duration::from_secs(0),
+            UnipolarFloat::ONE,
+            Duration::from_secs(1),
+        ))
+    }
+
+    fn event(crossfade: Option<Duration>) -> ColorEventStrong<HsluvColor> {
+        let color = HsluvColor::new(Phase::ZERO, UnipolarFloat::ONE, UnipolarFloat::ONE);
+        let mut event = ColorEvent::new(color, envelope(Duration::from_secs(4)), 0);
+        if let Some(attack) = crossfade {
+            event = event.with_crossfade(envelope(attack));
+        }
+        Rc::new(RefCell::new(event))
+    }
+
+    #[test]
+    /// A fast crossfade takes over from older events before the brightness
+    /// envelope completes its attack.
+    fn test_crossfade() {
+        let mut fixture = Fixture::new();
+        let old = event(None);
+        let new = event(Some(Duration::from_secs(1)));
+        fixture.add_event(old.clone());
+        fixture.add_event(new.clone());
+
+        new.borrow_mut().update_state(Duration::from_millis(500));
+        assert_eq!(UnipolarFloat::new(0.5), new.borrow().crossfade_weight());
+        fixture.update_state();
+        assert_eq!(2, fixture.event_buffer.len());
+
+        new.borrow_mut().update_state(Duration::from_millis(1000));
+        assert!(!new.borrow().envelope().attack_complete());
+        assert!(new.borrow().takeover_complete());
+        fixture.update_state();
+        assert_eq!(1, fixture.event_buffer.len());
+    }
+
+    #[test]
+    /// In LTP mode, a new event crossfades in over the old one during its
+    /// attack rather than replacing it outright.
+    fn test_ltp_crossfade() {
+        let mut fixture = Fixture::new();
+        let old_color = HsluvColor::new(Phase::ZERO, UnipolarFloat::ONE, UnipolarFloat::ONE);
+        let old = Rc::new(RefCell::new(ColorEvent::new(
+            old_color,
+            envelope(Duration::from_secs(0)),
+            0,
+        )));
+        let new = event(Some(Duration::from_secs(1)));
+        new.borrow_mut().set_color(HsluvColor::new(
+            Phase::new(0.5),
+            UnipolarFloat::ONE,
+            UnipolarFloat::ONE,
+        ));
+        fixture.add_event(old.clone());
+        fixture.add_event(new.clone());
+
+        new.borrow_mut().update_state(Duration::from_millis(500));
+        let (a, b) = (
+            old.borrow().value().linear_rgb(),
+            new.borrow().value().linear_rgb(),
+        );
+        let rendered = fixture.render(BlendMode::Ltp).linear_rgb();
+        for i in 0..3 {
+            assert!((0.5 * (a[i] + b[i]) - rendered[i]).abs() < 1e-6);
+        }
+        // The old color is still mostly visible while the new one is dim.
+        assert!(rendered[0] > 0.4);
+
+        new.borrow_mut().update_state(Duration::from_millis(1000));
+        let rendered = fixture.render(BlendMode::Ltp).linear_rgb();
+        let b = new.borrow().value().linear_rgb();
+        for i in 0..3 {
+            assert!((b[i] - rendered[i]).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    /// Without a crossfade envelope, the brightness envelope is the weight.
+    fn test_no_crossfade() {
+        let event = event(None);
+        event.borrow_mut().update_state(Duration::from_secs(1));
+        assert_eq!(UnipolarFloat::new(0.25), event.borrow().crossfade_weight());
+    }
+}
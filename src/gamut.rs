@@ -0,0 +1,372 @@
+//! Gamut mapping for fixtures with measured emitter primaries.
+
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+
+use crate::hsluv;
+use crate::rgb::RgbColor;
+
+/// Chroma below this fraction of a fixture's maximum chroma is reproduced
+/// exactly; chroma above it is smoothly compressed into the remaining range.
+const COMPRESSION_KNEE: f64 = 0.8;
+
+/// The number of bisection steps used to find the edge of a fixture's gamut.
+const GAMUT_SEARCH_STEPS: usize = 32;
+
+/// Emitter levels within this distance of the unit range are in gamut.
+const LEVEL_TOLERANCE: f64 = 1e-9;
+
+/// The measured output of one emitter at full level.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Primary {
+    /// CIE 1931 x chromaticity.
+    pub x: f64,
+    /// CIE 1931 y chromaticity.
+    pub y: f64,
+    /// Luminous flux, in any unit shared by the fixture's primaries.
+    pub lumens: f64,
+}
+
+impl Primary {
+    pub fn new(x: f64, y: f64, lumens: f64) -> Self {
+        Self { x, y, lumens }
+    }
+
+    /// Return the CIE XYZ of this primary, with luminance relative to the
+    /// provided total.
+    fn xyz(&self, total_lumens: f64) -> [f64; 3] {
+        let luminance = self.lumens / total_lumens;
+        [
+            self.x / self.y * luminance,
+            luminance,
+            (1. - self.x - self.y) / self.y * luminance,
+        ]
+    }
+}
+
+/// The measured red, green and blue primaries of a fixture.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Primaries {
+    pub red: Primary,
+    pub green: Primary,
+    pub blue: Primary,
+}
+
+/// The gamut of a fixture, used to solve for the emitter levels that best
+/// match a color.
+///
+/// Luminance is relative to the fixture with every emitter at full, so full
+/// white on the fixture matches full white in sRGB.  Colors the fixture cannot
+/// reproduce keep their hue and lightness, and have their chroma compressed
+/// into the fixture's gamut.  Chroma near the edge of the gamut is compressed
+/// too, so that gradients approaching the edge don't flatten abruptly.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Primaries", into = "Primaries")]
+pub struct FixtureGamut {
+    primaries: Primaries,
+    /// The matrix from CIE XYZ to linear emitter levels.
+    to_levels: [[f64; 3]; 3],
+}
+
+impl FixtureGamut {
+    pub fn new(primaries: Primaries) -> Result<Self, GamutError> {
+        let all = [primaries.red, primaries.green, primaries.blue];
+        for (i, p) in all.iter().enumerate() {
+            if p.x < 0. || p.y <= 0. || p.x + p.y > 1. {
+                return Err(GamutError::Chromaticity(i));
+            }
+            if p.lumens <= 0. {
+                return Err(GamutError::Lumens(i));
+            }
+        }
+        let total_lumens: f64 = all.iter().map(|p| p.lumens).sum();
+        let [r, g, b] = [
+            all[0].xyz(total_lumens),
+            all[1].xyz(total_lumens),
+            all[2].xyz(total_lumens),
+        ];
+        // The primaries are the columns of the matrix from levels to XYZ.
+        let to_xyz = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+        let to_levels = invert(to_xyz).ok_or(GamutError::Degenerate)?;
+        Ok(Self {
+            primaries,
+            to_levels,
+        })
+    }
+
+    pub fn primaries(&self) -> &Primaries {
+        &self.primaries
+    }
+
+    /// Return the linear emitter levels that render the provided color.
+    pub fn levels<C: RgbColor>(&self, color: &C) -> [f64; 3] {
+        self.map(hsluv::linear_rgb_to_xyz(color.linear_rgb()))
+    }
+
+    /// Return the linear emitter levels that render the provided CIE XYZ
+    /// color, compressing it into the gamut of this fixture.
+    pub fn map(&self, xyz: [f64; 3]) -> [f64; 3] {
+        let [l, c, h] = hsluv::luv_to_lch(hsluv::xyz_to_luv(xyz));
+        if l <= 0. {
+            return [0., 0., 0.];
+        }
+
+        // If the fixture can't produce a gray this bright, dim the color to
+        // the brightest gray it can.
+        let gray = self.solve(lch_to_xyz([l, 0., h]));
+        let brightest = gray[0].max(gray[1]).max(gray[2]);
+        let l = if brightest > 1. {
+            let [x, y, z] = xyz;
+            hsluv::xyz_to_luv([x / brightest, y / brightest, z / brightest])[0]
+        } else {
+            l
+        };
+
+        let chroma = compress(c, self.max_chroma(l, h));
+        let levels = self.solve(lch_to_xyz([l, chroma, h]));
+        [
+            levels[0].clamp(0., 1.),
+            levels[1].clamp(0., 1.),
+            levels[2].clamp(0., 1.),
+        ]
+    }
+
+    /// Return the emitter levels for the provided CIE XYZ color, which may
+    /// fall outside the unit range if the color is out of gamut.
+    fn solve(&self, xyz: [f64; 3]) -> [f64; 3] {
+        let m = &self.to_levels;
+        [dot(&m[0], &xyz), dot(&m[1], &xyz), dot(&m[2], &xyz)]
+    }
+
+    fn in_gamut(&self, lch: [f64; 3]) -> bool {
+        self.solve(lch_to_xyz(lch))
+            .iter()
+            .all(|level| *level >= -LEVEL_TOLERANCE && *level <= 1. + LEVEL_TOLERANCE)
+    }
+
+    /// Return the maximum chroma this fixture can produce at the provided
+    /// lightness and hue.
+    /// The gamut is convex, so every chroma below the maximum is in gamut.
+    fn max_chroma(&self, l: f64, h: f64) -> f64 {
+        if !self.in_gamut([l, 0., h]) {
+            return 0.;
+        }
+        // Find a chroma outside the gamut, then bisect toward its edge.
+        let mut high = 100.;
+        while self.in_gamut([l, high, h]) {
+            high *= 2.;
+        }
+        let mut low = 0.;
+        for _ in 0..GAMUT_SEARCH_STEPS {
+            let mid = (low + high) / 2.;
+            if self.in_gamut([l, mid, h]) {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+}
+
+impl TryFrom<Primaries> for FixtureGamut {
+    type Error = GamutError;
+
+    fn try_from(primaries: Primaries) -> Result<Self, Self::Error> {
+        Self::new(primaries)
+    }
+}
+
+impl From<FixtureGamut> for Primaries {
+    fn from(gamut: FixtureGamut) -> Self {
+        gamut.primaries
+    }
+}
+
+/// Reasons a set of primaries may be rejected.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GamutError {
+    /// The primary at this index, in red, green, blue order, is not a valid
+    /// chromaticity.
+    Chromaticity(usize),
+    /// The primary at this index, in red, green, blue order, has no output.
+    Lumens(usize),
+    /// The primaries lie on a line, so they don't enclose a gamut.
+    Degenerate,
+}
+
+impl fmt::Display for GamutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use GamutError::*;
+        match self {
+            Chromaticity(i) => write!(f, "primary {} is not a valid chromaticity", i),
+            Lumens(i) => write!(f, "primary {} must have positive lumens", i),
+            Degenerate => write!(f, "the primaries do not enclose a gamut"),
+        }
+    }
+}
+
+impl Error for GamutError {}
+
+/// Compress chroma above the knee smoothly into the range below the limit.
+fn compress(chroma: f64, limit: f64) -> f64 {
+    let threshold = COMPRESSION_KNEE * limit;
+    if chroma <= threshold {
+        return chroma;
+    }
+    let range = limit - threshold;
+    threshold + range * ((chroma - threshold) / range).tanh()
+}
+
+fn lch_to_xyz(lch: [f64; 3]) -> [f64; 3] {
+    hsluv::luv_to_xyz(hsluv::lch_to_luv(lch))
+}
+
+/// Invert a 3x3 matrix, or return None if it is singular.
+fn invert(m: [[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let cofactor = |r: usize, c: usize| {
+        let (r0, r1) = ((r + 1) % 3, (r + 2) % 3);
+        let (c0, c1) = ((c + 1) % 3, (c + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let det = m[0][0] * cofactor(0, 0) + m[0][1] * cofactor(0, 1) + m[0][2] * cofactor(0, 2);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let mut inverse = [[0.; 3]; 3];
+    for (r, row) in inverse.iter_mut().enumerate() {
+        for (c, value) in row.iter_mut().enumerate() {
+            // The inverse is the transposed cofactor matrix over the determinant.
+            *value = cofactor(c, r) / det;
+        }
+    }
+    Some(inverse)
+}
+
+fn dot(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+#[cfg(test)]
+mod test {
+    use number::{Phase, UnipolarFloat};
+
+    use super::*;
+    use crate::color::HsluvColor;
+
+    /// Primaries matching sRGB.
+    fn srgb() -> Primaries {
+        Primaries {
+            red: Primary::new(0.64, 0.33, 0.2126),
+            green: Primary::new(0.30, 0.60, 0.7152),
+            blue: Primary::new(0.15, 0.06, 0.0722),
+        }
+    }
+
+    fn assert_close(expected: [f64; 3], actual: [f64; 3]) {
+        for i in 0..3 {
+            assert!(
+                (expected[i] - actual[i]).abs() < 1e-3,
+                "expected {:?}, got {:?}",
+                expected,
+                actual
+            );
+        }
+    }
+
+    fn lch(levels: [f64; 3], gamut: &FixtureGamut) -> [f64; 3] {
+        let to_xyz = invert(gamut.to_levels).unwrap();
+        let xyz = [
+            dot(&to_xyz[0], &levels),
+            dot(&to_xyz[1], &levels),
+            dot(&to_xyz[2], &levels),
+        ];
+        hsluv::luv_to_lch(hsluv::xyz_to_luv(xyz))
+    }
+
+    fn hue(levels: [f64; 3], gamut: &FixtureGamut) -> f64 {
+        lch(levels, gamut)[2]
+    }
+
+    #[test]
+    /// Colors with chroma below the compression knee are reproduced exactly.
+    fn test_in_gamut() {
+        let gamut = FixtureGamut::new(srgb()).unwrap();
+        assert_close(
+            [0.5, 0.5, 0.5],
+            gamut.map(hsluv::linear_rgb_to_xyz([0.5; 3])),
+        );
+        assert_close(
+            [0.3, 0.4, 0.5],
+            gamut.map(hsluv::linear_rgb_to_xyz([0.3, 0.4, 0.5])),
+        );
+        assert_close([1., 1., 1.], gamut.map(hsluv::linear_rgb_to_xyz([1.; 3])));
+        assert_eq!([0., 0., 0.], gamut.map([0., 0., 0.]));
+    }
+
+    #[test]
+    /// Colors inside the gamut with chroma above the knee are desaturated
+    /// slightly, keeping their lightness and hue.
+    fn test_knee() {
+        let gamut = FixtureGamut::new(srgb()).unwrap();
+        let (l, h) = (50., 120.);
+        let limit = gamut.max_chroma(l, h);
+        let color = |fraction: f64| lch_to_xyz([l, fraction * limit, h]);
+
+        assert_close([l, 0.7 * limit, h], lch(gamut.map(color(0.7)), &gamut));
+        let expected = (COMPRESSION_KNEE + (1. - COMPRESSION_KNEE) * 0.5_f64.tanh()) * limit;
+        assert_close([l, expected, h], lch(gamut.map(color(0.9)), &gamut));
+        assert!(expected < 0.9 * limit);
+    }
+
+    #[test]
+    /// Colors outside the gamut of a fixture keep their hue, and are
+    /// compressed rather than clipped.
+    fn test_compression() {
+        let narrow = FixtureGamut::new(Primaries {
+            red: Primary::new(0.55, 0.35, 0.25),
+            green: Primary::new(0.32, 0.50, 0.65),
+            blue: Primary::new(0.18, 0.12, 0.1),
+        })
+        .unwrap();
+        let saturated = |s: f64| {
+            HsluvColor::new(
+                Phase::new(0.7),
+                UnipolarFloat::new(s),
+                UnipolarFloat::new(0.4),
+            )
+        };
+        let target_hue = 0.7 * 360.;
+        let full = narrow.levels(&saturated(1.));
+        assert!(full.iter().all(|l| (0. ..=1.).contains(l)));
+        let less = narrow.levels(&saturated(0.3));
+        for levels in &[full, less] {
+            assert!((target_hue - hue(*levels, &narrow)).abs() < 1e-3);
+        }
+
+        // Chroma is compressed smoothly toward the edge of the gamut.
+        assert_eq!(0.5, compress(0.5, 1.));
+        assert!(compress(0.9, 1.) < 0.9);
+        assert!(compress(0.9, 1.) < compress(1., 1.));
+        assert!(compress(1., 1.) < compress(2., 1.));
+        assert!(compress(2., 1.) < 1.);
+    }
+
+    #[test]
+    fn test_invalid_primaries() {
+        let mut primaries = srgb();
+        primaries.green.y = 0.;
+        assert_eq!(
+            Err(GamutError::Chromaticity(1)),
+            FixtureGamut::new(primaries)
+        );
+        let mut primaries = srgb();
+        primaries.blue.lumens = 0.;
+        assert_eq!(Err(GamutError::Lumens(2)), FixtureGamut::new(primaries));
+        let mut primaries = srgb();
+        primaries.blue = Primary::new(0.47, 0.465, 0.1);
+        assert_eq!(Err(GamutError::Degenerate), FixtureGamut::new(primaries));
+    }
+}
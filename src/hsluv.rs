@@ -0,0 +1,230 @@
+//! Conversions between HSLuv and sRGB, by way of CIE LCh(uv), LUV and XYZ.
+//! See https://www.hsluv.org for the definition of the color space.
+//!
+//! HSLuv components are hue in degrees, and saturation and lightness as
+//! percentages.  RGB components are nominally in the range [0, 1], but may fall
+//! slightly outside it due to rounding.
+
+/// The matrix from CIE XYZ to linear sRGB.
+const M: [[f64; 3]; 3] = [
+    [3.240969941904521, -1.537383177570093, -0.498610760293],
+    [-0.96924363628087, 1.87596750150772, 0.041555057407175],
+    [0.055630079696993, -0.20397695888897, 1.056971514242878],
+];
+
+/// The matrix from linear sRGB to CIE XYZ.
+const M_INV: [[f64; 3]; 3] = [
+    [0.41239079926595, 0.35758433938387, 0.18048078840183],
+    [0.21263900587151, 0.71516867876775, 0.072192315360733],
+    [0.019330818715591, 0.11919477979462, 0.95053215224966],
+];
+
+/// The u' and v' chromaticity of the D65 white point.
+const REF_U: f64 = 0.19783000664283;
+const REF_V: f64 = 0.46831999493879;
+
+/// CIE constants relating lightness and luminance.
+const KAPPA: f64 = 903.2962962;
+const EPSILON: f64 = 0.0088564516;
+
+/// Lightness values within this distance of black or white have no chroma.
+const LIGHTNESS_EPSILON: f64 = 1e-8;
+
+/// Convert HSLuv to gamma-encoded sRGB.
+pub fn hsluv_to_rgb(hsl: [f64; 3]) -> [f64; 3] {
+    let linear = xyz_to_linear_rgb(luv_to_xyz(lch_to_luv(hsluv_to_lch(hsl))));
+    [
+        from_linear(linear[0]),
+        from_linear(linear[1]),
+        from_linear(linear[2]),
+    ]
+}
+
+/// Convert gamma-encoded sRGB to HSLuv.
+pub fn rgb_to_hsluv(rgb: [f64; 3]) -> [f64; 3] {
+    let linear = [to_linear(rgb[0]), to_linear(rgb[1]), to_linear(rgb[2])];
+    lch_to_hsluv(luv_to_lch(xyz_to_luv(linear_rgb_to_xyz(linear))))
+}
+
+/// Convert HSLuv to CIE LCh(uv).
+pub fn hsluv_to_lch([h, s, l]: [f64; 3]) -> [f64; 3] {
+    if l > 100. - LIGHTNESS_EPSILON {
+        return [100., 0., h];
+    }
+    if l < LIGHTNESS_EPSILON {
+        return [0., 0., h];
+    }
+    [l, max_chroma(l, h) / 100. * s, h]
+}
+
+/// Convert CIE LCh(uv) to HSLuv.
+pub fn lch_to_hsluv([l, c, h]: [f64; 3]) -> [f64; 3] {
+    if l > 100. - LIGHTNESS_EPSILON {
+        return [h, 0., 100.];
+    }
+    if l < LIGHTNESS_EPSILON {
+        return [h, 0., 0.];
+    }
+    [h, c / max_chroma(l, h) * 100., l]
+}
+
+/// Convert CIE LCh(uv) to CIE LUV.
+pub fn lch_to_luv([l, c, h]: [f64; 3]) -> [f64; 3] {
+    let h = h.to_radians();
+    [l, c * h.cos(), c * h.sin()]
+}
+
+/// Convert CIE LUV to CIE LCh(uv).
+pub fn luv_to_lch([l, u, v]: [f64; 3]) -> [f64; 3] {
+    let c = u.hypot(v);
+    let h = if c < LIGHTNESS_EPSILON {
+        0.
+    } else {
+        v.atan2(u).to_degrees().rem_euclid(360.)
+    };
+    [l, c, h]
+}
+
+/// Convert CIE LUV to CIE XYZ.
+pub fn luv_to_xyz([l, u, v]: [f64; 3]) -> [f64; 3] {
+    if l == 0. {
+        return [0., 0., 0.];
+    }
+    let var_u = u / (13. * l) + REF_U;
+    let var_v = v / (13. * l) + REF_V;
+    let y = l_to_y(l);
+    let x = -(9. * y * var_u) / ((var_u - 4.) * var_v - var_u * var_v);
+    let z = (9. * y - 15. * var_v * y - var_v * x) / (3. * var_v);
+    [x, y, z]
+}
+
+/// Convert CIE XYZ to CIE LUV.
+pub fn xyz_to_luv([x, y, z]: [f64; 3]) -> [f64; 3] {
+    let l = y_to_l(y);
+    let divider = x + 15. * y + 3. * z;
+    if l == 0. || divider == 0. {
+        return [0., 0., 0.];
+    }
+    let var_u = 4. * x / divider;
+    let var_v = 9. * y / divider;
+    [l, 13. * l * (var_u - REF_U), 13. * l * (var_v - REF_V)]
+}
+
+/// Convert CIE XYZ to linear sRGB.
+pub fn xyz_to_linear_rgb(xyz: [f64; 3]) -> [f64; 3] {
+    [dot(&M[0], &xyz), dot(&M[1], &xyz), dot(&M[2], &xyz)]
+}
+
+/// Convert linear sRGB to CIE XYZ.
+pub fn linear_rgb_to_xyz(rgb: [f64; 3]) -> [f64; 3] {
+    [
+        dot(&M_INV[0], &rgb),
+        dot(&M_INV[1], &rgb),
+        dot(&M_INV[2], &rgb),
+    ]
+}
+
+/// Apply the sRGB transfer function to a linear component.
+pub fn from_linear(c: f64) -> f64 {
+    if c <= 0.0031308 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1. / 2.4) - 0.055
+    }
+}
+
+/// Remove the sRGB transfer function from a gamma-encoded component.
+pub fn to_linear(c: f64) -> f64 {
+    if c > 0.04045 {
+        ((c + 0.055) / 1.055).powf(2.4)
+    } else {
+        c / 12.92
+    }
+}
+
+/// Return the maximum chroma within the sRGB gamut for the provided lightness
+/// and hue in degrees.
+fn max_chroma(l: f64, h: f64) -> f64 {
+    let h = h.to_radians();
+    bounds(l)
+        .iter()
+        .map(|(slope, intercept)| intercept / (h.sin() - slope * h.cos()))
+        .filter(|length| *length >= 0.)
+        .fold(f64::INFINITY, f64::min)
+}
+
+/// Return the lines bounding the sRGB gamut in the LUV plane at the provided
+/// lightness, as (slope, intercept) pairs.
+fn bounds(l: f64) -> [(f64, f64); 6] {
+    let sub1 = (l + 16.).powi(3) / 1_560_896.;
+    let sub2 = if sub1 > EPSILON { sub1 } else { l / KAPPA };
+    let mut lines = [(0., 0.); 6];
+    for (c, [m1, m2, m3]) in M.iter().enumerate() {
+        for t in 0..2 {
+            let t = t as f64;
+            let top1 = (284_517. * m1 - 94_839. * m3) * sub2;
+            let top2 =
+                (838_422. * m3 + 769_860. * m2 + 731_718. * m1) * l * sub2 - 769_860. * t * l;
+            let bottom = (632_260. * m3 - 126_452. * m2) * sub2 + 126_452. * t;
+            lines[2 * c + t as usize] = (top1 / bottom, top2 / bottom);
+        }
+    }
+    lines
+}
+
+fn l_to_y(l: f64) -> f64 {
+    if l <= 8. {
+        l / KAPPA
+    } else {
+        ((l + 16.) / 116.).powi(3)
+    }
+}
+
+fn y_to_l(y: f64) -> f64 {
+    if y <= EPSILON {
+        y * KAPPA
+    } else {
+        116. * y.cbrt() - 16.
+    }
+}
+
+fn dot(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_close(expected: [f64; 3], actual: [f64; 3]) {
+        for i in 0..3 {
+            assert!(
+                (expected[i] - actual[i]).abs() < 1e-6,
+                "expected {:?}, got {:?}",
+                expected,
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for rgb in &[
+            [1., 0., 0.],
+            [0., 1., 0.],
+            [0., 0., 1.],
+            [1., 1., 1.],
+            [0.2, 0.4, 0.6],
+            [0.9, 0.1, 0.5],
+        ] {
+            assert_close(*rgb, hsluv_to_rgb(rgb_to_hsluv(*rgb)));
+        }
+    }
+
+    #[test]
+    fn test_black_and_white() {
+        assert_close([0., 0., 0.], hsluv_to_rgb([120., 100., 0.]));
+        assert_close([1., 1., 1.], hsluv_to_rgb([120., 100., 100.]));
+        assert_eq!(0., rgb_to_hsluv([0.5, 0.5, 0.5])[1].round());
+    }
+}
@@ -0,0 +1,149 @@
+//! Randomized variation of envelope parameters.
+
+use number::UnipolarFloat;
+use std::time::Duration;
+
+use crate::envelope::EnvelopeParameters;
+
+/// The largest fractional change to envelope times at full humanization.
+const MAX_TIME_JITTER: f64 = 0.5;
+
+/// The largest fractional change to the sustain level at full humanization.
+const MAX_LEVEL_JITTER: f64 = 0.25;
+
+/// Apply seeded random jitter to envelope parameters, so that events do not
+/// all evolve in lockstep.
+#[derive(Clone)]
+pub struct Humanizer {
+    amount: UnipolarFloat,
+    seed: u64,
+    rng: Rng,
+}
+
+impl Humanizer {
+    pub fn new() -> Self {
+        Self {
+            amount: UnipolarFloat::ZERO,
+            seed: 0,
+            rng: Rng(0),
+        }
+    }
+
+    /// Return the amount of jitter applied.
+    pub fn amount(&self) -> UnipolarFloat {
+        self.amount
+    }
+
+    pub fn set_amount(&mut self, amount: UnipolarFloat) {
+        self.amount = amount;
+    }
+
+    /// Return the seed this humanizer was last seeded with.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Restart the random sequence from the provided seed.
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = Rng(seed);
+    }
+
+    /// Apply random jitter to the attack, decay, release and sustain level.
+    /// A sustain level of zero is left unchanged.
+    pub fn apply(&mut self, mut params: EnvelopeParameters) -> EnvelopeParameters {
+        if self.amount == UnipolarFloat::ZERO {
+            return params;
+        }
+        params.attack = self.jitter(params.attack, MAX_TIME_JITTER);
+        params.decay = self.jitter(params.decay, MAX_TIME_JITTER);
+        params.release = self.jitter(params.release, MAX_TIME_JITTER);
+        params.sustain_level =
+            UnipolarFloat::new(params.sustain_level.val() * self.jitter_factor(MAX_LEVEL_JITTER));
+        params
+    }
+
+    /// Randomly scale a duration by up to the provided fraction of itself.
+    fn jitter(&mut self, duration: Duration, max: f64) -> Duration {
+        duration.mul_f64(self.jitter_factor(max))
+    }
+
+    /// Return a random scale factor that differs from 1 by up to the provided
+    /// maximum, scaled by the humanization amount.
+    fn jitter_factor(&mut self, max: f64) -> f64 {
+        1. + self.amount.val() * max * self.rng.bipolar()
+    }
+}
+
+/// A small, seedable pseudorandom number generator (SplitMix64).
+#[derive(Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Return a uniformly-distributed value in [-1, 1).
+    fn bipolar(&mut self) -> f64 {
+        let unit = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        2. * unit - 1.
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn params() -> EnvelopeParameters {
+        EnvelopeParameters::linear(
+            Duration::from_secs(1),
+            UnipolarFloat::ZERO,
+            Duration::from_secs(1),
+            UnipolarFloat::new(0.5),
+            Duration::from_secs(1),
+        )
+    }
+
+    #[test]
+    fn test_no_jitter() {
+        let mut humanizer = Humanizer::new();
+        let jittered = humanizer.apply(params());
+        assert_eq!(Duration::from_secs(1), jittered.attack);
+        assert_eq!(UnipolarFloat::new(0.5), jittered.sustain_level);
+    }
+
+    #[test]
+    fn test_jitter_bounds() {
+        let mut humanizer = Humanizer::new();
+        humanizer.set_amount(UnipolarFloat::ONE);
+        let mut differ = false;
+        for _ in 0..100 {
+            let jittered = humanizer.apply(params());
+            for t in &[jittered.attack, jittered.decay, jittered.release] {
+                assert!(*t >= Duration::from_millis(500));
+                assert!(*t <= Duration::from_millis(1500));
+            }
+            assert!(jittered.sustain_level >= UnipolarFloat::new(0.375));
+            assert!(jittered.sustain_level <= UnipolarFloat::new(0.625));
+            differ |= jittered.attack != jittered.decay;
+        }
+        assert!(differ);
+    }
+
+    #[test]
+    fn test_seed() {
+        let mut humanizer = Humanizer::new();
+        humanizer.set_amount(UnipolarFloat::ONE);
+        humanizer.reseed(42);
+        let first = humanizer.apply(params());
+        humanizer.reseed(42);
+        let second = humanizer.apply(params());
+        assert_eq!(first.attack, second.attack);
+        assert_eq!(first.sustain_level, second.sustain_level);
+    }
+}
@@ -1,11 +1,24 @@
 mod bank;
+mod breakpoint;
+mod choke;
+mod clock;
 mod color;
+mod edge;
+mod emitter;
 mod envelope;
 mod envelope_gen;
 mod event;
 mod fixture;
+mod gamut;
+mod hsluv;
+mod humanize;
+mod modulation;
 mod organ;
 mod patch;
+mod pedal;
+mod preset;
+mod preview;
+mod rgb;
 mod store;
 
 use std::{thread::sleep, time::Duration};
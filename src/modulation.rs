@@ -0,0 +1,294 @@
+//! Modulation of color parameters other than brightness by envelopes.
+
+use number::{BipolarFloat, UnipolarFloat};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::color::Color;
+use crate::envelope::{Envelope, EnvelopeParameters};
+use crate::organ::{EmitStateChange as EmitOrganStateChange, StateChange as OrganStateChange};
+
+/// The envelope that drives a modulation route.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModulationSource {
+    /// The envelope of the event itself, which also drives its brightness.
+    Envelope,
+    /// A second envelope, triggered and released along with the event.
+    Secondary,
+}
+
+/// Routing of an envelope to a single modulation target.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Route {
+    pub source: ModulationSource,
+    /// Positive depths apply more modulation as the envelope rises; negative
+    /// depths apply full modulation at the bottom of the envelope, and remove
+    /// it as the envelope rises.  A depth of zero disables the route.
+    pub depth: BipolarFloat,
+}
+
+impl Route {
+    pub fn new(source: ModulationSource, depth: BipolarFloat) -> Self {
+        Self { source, depth }
+    }
+
+    /// Return true if this route has any effect.
+    fn active(&self) -> bool {
+        self.depth != BipolarFloat::ZERO
+    }
+
+    /// Return the amount of modulation for the provided envelope value.
+    fn amount(&self, envelope: UnipolarFloat) -> UnipolarFloat {
+        let depth = self.depth.val();
+        UnipolarFloat::new(if depth >= 0. {
+            depth * envelope.val()
+        } else {
+            -depth * (1. - envelope.val())
+        })
+    }
+}
+
+impl Default for Route {
+    fn default() -> Self {
+        Self::new(ModulationSource::Envelope, BipolarFloat::ZERO)
+    }
+}
+
+/// The routes from envelopes to every modulation target.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Routes {
+    /// Shift the hue by up to the depth, in fractions of a full turn.
+    /// Unlike the other targets, a negative depth shifts the hue in the
+    /// opposite direction.
+    pub hue: Route,
+    /// Desaturate the color.
+    pub saturation: Route,
+    /// Mix white into the color.
+    pub white: Route,
+}
+
+impl Routes {
+    /// Return true if any route uses the secondary envelope.
+    fn uses_secondary(&self) -> bool {
+        [self.hue, self.saturation, self.white]
+            .iter()
+            .any(|r| r.active() && r.source == ModulationSource::Secondary)
+    }
+}
+
+/// The modulation state of a single color event.
+pub struct Modulation {
+    routes: Routes,
+    /// The secondary envelope, only present if a route uses it.
+    secondary: Option<Envelope>,
+}
+
+impl Modulation {
+    /// Return modulation that has no effect.
+    pub fn none() -> Self {
+        Self {
+            routes: Routes::default(),
+            secondary: None,
+        }
+    }
+
+    /// Return the provided color with modulation applied, given the value of
+    /// the event's own envelope.
+    pub fn apply<C: Color>(&self, color: &C, envelope: Option<UnipolarFloat>) -> C {
+        let envelope = envelope.unwrap_or(UnipolarFloat::ZERO);
+        let secondary = self
+            .secondary
+            .as_ref()
+            .and_then(Envelope::value)
+            .unwrap_or(UnipolarFloat::ZERO);
+        let source = |route: &Route| match route.source {
+            ModulationSource::Envelope => envelope,
+            ModulationSource::Secondary => secondary,
+        };
+        let mut color = color.clone();
+        let hue = &self.routes.hue;
+        if hue.active() {
+            color = color.with_hue_shift(hue.depth.val() * source(hue).val());
+        }
+        let saturation = &self.routes.saturation;
+        if saturation.active() {
+            color =
+                color.with_saturation(UnipolarFloat::ONE - saturation.amount(source(saturation)));
+        }
+        let white = &self.routes.white;
+        if white.active() {
+            color = color.with_white(white.amount(source(white)));
+        }
+        color
+    }
+
+    /// Release the secondary envelope.
+    pub fn release(&mut self) {
+        if let Some(secondary) = self.secondary.as_mut() {
+            secondary.release();
+        }
+    }
+
+    /// Restart the secondary envelope from its current level.
+    pub fn restart(&mut self) {
+        if let Some(secondary) = self.secondary.as_mut() {
+            secondary.restart();
+        }
+    }
+
+    /// Update the state of the secondary envelope.
+    pub fn update_state(&mut self, delta_t: Duration) {
+        if let Some(secondary) = self.secondary.as_mut() {
+            secondary.update_state(delta_t);
+        }
+    }
+}
+
+/// Configure modulation for new color events.
+pub struct ModulationMatrix {
+    routes: Routes,
+    /// The parameters of the secondary envelope.
+    secondary: EnvelopeParameters,
+}
+
+impl ModulationMatrix {
+    pub fn new() -> Self {
+        Self {
+            routes: Routes::default(),
+            secondary: EnvelopeParameters::linear(
+                Duration::from_secs(1),
+                UnipolarFloat::ZERO,
+                Duration::from_secs(1),
+                UnipolarFloat::ONE,
+                Duration::from_secs(1),
+            ),
+        }
+    }
+
+    /// Return modulation state for a new color event.
+    pub fn modulation(&self) -> Modulation {
+        Modulation {
+            routes: self.routes,
+            secondary: if self.routes.uses_secondary() {
+                Some(Envelope::new(self.secondary.clone()))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Emit all observable state using the provided emitter.
+    pub fn emit_state<E: EmitStateChange>(&self, emitter: &mut E) {
+        use StateChange::*;
+        emitter.emit_modulation_state_change(Hue(self.routes.hue));
+        emitter.emit_modulation_state_change(Saturation(self.routes.saturation));
+        emitter.emit_modulation_state_change(White(self.routes.white));
+        emitter.emit_modulation_state_change(Secondary(self.secondary.clone()));
+    }
+
+    /// Handle a control message.
+    pub fn control<E: EmitStateChange>(&mut self, msg: ControlMessage, emitter: &mut E) {
+        use ControlMessage::*;
+        match msg {
+            Set(sc) => self.handle_state_change(sc, emitter),
+        }
+    }
+
+    fn handle_state_change<E: EmitStateChange>(&mut self, sc: StateChange, emitter: &mut E) {
+        use StateChange::*;
+        match sc {
+            Hue(v) => self.routes.hue = v,
+            Saturation(v) => self.routes.saturation = v,
+            White(v) => self.routes.white = v,
+            Secondary(ref v) => self.secondary = v.clone(),
+        };
+        emitter.emit_modulation_state_change(sc);
+    }
+}
+
+pub enum ControlMessage {
+    Set(StateChange),
+}
+
+pub enum StateChange {
+    Hue(Route),
+    Saturation(Route),
+    White(Route),
+    /// The parameters of the secondary envelope.
+    Secondary(EnvelopeParameters),
+}
+
+pub trait EmitStateChange {
+    fn emit_modulation_state_change(&mut self, sc: StateChange);
+}
+
+impl<T: EmitOrganStateChange> EmitStateChange for T {
+    fn emit_modulation_state_change(&mut self, sc: StateChange) {
+        self.emit_state_change(OrganStateChange::Modulation(sc));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use number::Phase;
+
+    use super::*;
+    use crate::color::HsluvColor;
+    use crate::organ::Discard;
+
+    fn color() -> HsluvColor {
+        HsluvColor::new(Phase::ZERO, UnipolarFloat::ONE, UnipolarFloat::new(0.5))
+    }
+
+    #[test]
+    fn test_no_modulation() {
+        let modulated = Modulation::none().apply(&color(), Some(UnipolarFloat::new(0.5)));
+        assert_eq!(Phase::ZERO, modulated.hue);
+        assert_eq!(UnipolarFloat::ONE, modulated.saturation);
+        assert_eq!(UnipolarFloat::new(0.5), modulated.lightness);
+    }
+
+    #[test]
+    fn test_hue_shift() {
+        let mut matrix = ModulationMatrix::new();
+        matrix.control(
+            ControlMessage::Set(StateChange::Hue(Route::new(
+                ModulationSource::Envelope,
+                BipolarFloat::new(-0.5),
+            ))),
+            &mut Discard,
+        );
+        let modulated = matrix
+            .modulation()
+            .apply(&color(), Some(UnipolarFloat::new(0.5)));
+        assert_eq!(Phase::new(0.75), modulated.hue);
+    }
+
+    #[test]
+    /// A hit that starts white and blooms into its color as the secondary
+    /// envelope rises.
+    fn test_bloom() {
+        let mut matrix = ModulationMatrix::new();
+        for sc in [
+            StateChange::Saturation(Route::new(
+                ModulationSource::Secondary,
+                BipolarFloat::new(-1.),
+            )),
+            StateChange::White(Route::new(
+                ModulationSource::Secondary,
+                BipolarFloat::new(-1.),
+            )),
+        ] {
+            matrix.control(ControlMessage::Set(sc), &mut Discard);
+        }
+        let mut modulation = matrix.modulation();
+        let start = modulation.apply(&color(), Some(UnipolarFloat::ONE));
+        assert_eq!(UnipolarFloat::ZERO, start.saturation);
+        assert_eq!(UnipolarFloat::ONE, start.lightness);
+
+        modulation.update_state(Duration::from_secs(1));
+        let end = modulation.apply(&color(), Some(UnipolarFloat::ONE));
+        assert_eq!(UnipolarFloat::ONE, end.saturation);
+        assert_eq!(UnipolarFloat::new(0.5), end.lightness);
+    }
+}
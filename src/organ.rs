@@ -3,57 +3,165 @@ use std::{cell::RefCell, collections::HashMap, rc::Rc, time::Duration};
 use number::UnipolarFloat;
 
 use crate::{
-    bank::Banks, color::Color, envelope::Envelope, envelope_gen::EnvelopeGenerator,
-    event::ColorEvent, fixture::Fixture, patch::FixtureId, store::ColorEventStore,
+    bank::Banks,
+    choke::ChokeGroups,
+    clock::Clock,
+    color::{BlendMode, Color},
+    emitter::Emitters,
+    envelope::{Envelope, EnvelopeParameters},
+    envelope_gen::EnvelopeGenerator,
+    event::ColorEvent,
+    fixture::Fixture,
+    gamut::FixtureGamut,
+    modulation::{Modulation, ModulationMatrix},
+    patch::FixtureId,
+    pedal::Pedals,
+    preset::EnvelopePresets,
+    preview::EnvelopePreview,
+    rgb::{GamutMapping, Rgb},
+    store::{ColorEventStore, ColorEventStrong},
 };
 use crate::{
+    choke::{ControlMessage as ChokeControlMessage, StateChange as ChokeStateChange},
+    clock::{ControlMessage as ClockControlMessage, StateChange as ClockStateChange},
     envelope_gen::{ControlMessage as EnvelopeControlMessage, StateChange as EnvelopeStateChange},
     event::ReleaseID,
+    modulation::{
+        ControlMessage as ModulationControlMessage, StateChange as ModulationStateChange,
+    },
+    preset::{ControlMessage as PresetControlMessage, StateChange as PresetStateChange},
 };
 
 pub struct ColorOrgan<C: Color> {
+    retrigger: RetriggerMode,
+    /// If provided, new events use an envelope with these parameters to take
+    /// over from older events, instead of their brightness envelope.
+    crossfade: Option<EnvelopeParameters>,
+    pedals: Pedals,
+    choke_groups: ChokeGroups,
+    clock: Clock,
     envelope_gen: EnvelopeGenerator,
+    presets: EnvelopePresets,
+    modulation: ModulationMatrix,
     event_store: ColorEventStore<C>,
     banks: Banks,
     fixture_state: HashMap<FixtureId, Fixture<C>>,
+    /// The emitters of each fixture that has more than red, green and blue.
+    emitters: HashMap<FixtureId, Emitters>,
+    /// The measured gamut of each fixture that has been characterized.
+    gamuts: HashMap<FixtureId, FixtureGamut>,
+    /// How overlapping events are combined, unless overridden per fixture.
+    blend: BlendMode,
+    fixture_blend: HashMap<FixtureId, BlendMode>,
 }
 
 impl<C: Color> ColorOrgan<C> {
     pub fn new() -> Self {
         Self {
+            retrigger: RetriggerMode::Independent,
+            crossfade: None,
+            pedals: Pedals::new(),
+            choke_groups: ChokeGroups::new(),
+            clock: Clock::new(),
             envelope_gen: EnvelopeGenerator::new(),
+            presets: EnvelopePresets::new(),
+            modulation: ModulationMatrix::new(),
             event_store: ColorEventStore::new(),
             banks: Banks::new(),
             fixture_state: HashMap::new(),
+            emitters: HashMap::new(),
+            gamuts: HashMap::new(),
+            blend: BlendMode::default(),
+            fixture_blend: HashMap::new(),
         }
     }
 
     /// Handle a note on event.
     pub fn note_on(&mut self, color: C, velocity: UnipolarFloat, release_id: ReleaseID) {
-        let event = Rc::new(RefCell::new(ColorEvent::new(
-            color,
-            Envelope::new(self.envelope_gen.generate()),
-            release_id,
-        )));
-        self.event_store.add(&event);
+        self.pedals.note_on(release_id);
+        let event_store = &mut self.event_store;
+        self.choke_groups
+            .choked_by(release_id, |group| event_store.choke(group, release_id));
+        let params = self.envelope_gen.generate_with_overrides(
+            velocity,
+            &self.clock,
+            self.banks.envelope_overrides(),
+        );
+        if self.retrigger != RetriggerMode::Independent {
+            let sounding = self.event_store.sounding(release_id);
+            if !sounding.is_empty() {
+                for event in sounding {
+                    let mut event = event.borrow_mut();
+                    if self.retrigger == RetriggerMode::Legato && !event.envelope().released() {
+                        event.set_color(color.clone());
+                    } else {
+                        event.retrigger(color.clone(), self.envelope_gen.humanize(params.clone()));
+                    }
+                }
+                return;
+            }
+        }
+        let envelope_gen = &mut self.envelope_gen;
+        let modulation = &self.modulation;
+        let crossfade = &self.crossfade;
+        let event_store = &mut self.event_store;
+        // Unless each fixture gets its own envelope, all fixtures share one event.
+        let shared = if envelope_gen.humanize_per_fixture() {
+            None
+        } else {
+            let event = new_event(
+                color.clone(),
+                envelope_gen.humanize(params.clone()),
+                modulation.modulation(),
+                crossfade.clone().map(Envelope::new),
+                release_id,
+            );
+            event_store.add(&event);
+            Some(event)
+        };
         let fixture_state = &mut self.fixture_state;
         self.banks.next(velocity, |fixture_id| {
+            let event = match &shared {
+                Some(event) => event.clone(),
+                None => {
+                    let event = new_event(
+                        color.clone(),
+                        envelope_gen.humanize(params.clone()),
+                        modulation.modulation(),
+                        crossfade.clone().map(Envelope::new),
+                        release_id,
+                    );
+                    event_store.add(&event);
+                    event
+                }
+            };
             // Get the fixture state for this ID.
             // If this is the first event for this fixture, create it.
             fixture_state
                 .entry(fixture_id)
                 .or_insert_with(Fixture::new)
-                .add_event(event.clone());
+                .add_event(event);
         });
     }
 
     /// Handle a note off event.
-    /// Release all of the notes with the given release ID.
+    /// Release all of the notes with the given release ID, unless they are
+    /// held by a pedal.
     pub fn note_off(&mut self, release_id: ReleaseID) {
-        self.event_store.release(release_id);
+        if self.pedals.note_off(release_id) {
+            self.event_store.release(release_id);
+        }
+    }
+
+    /// Release all of the notes with the provided release IDs.
+    fn release_all(&mut self, release_ids: Vec<ReleaseID>) {
+        for release_id in release_ids {
+            self.event_store.release(release_id);
+        }
     }
 
     pub fn update_state(&mut self, delta_t: Duration) {
+        self.clock.update_state(delta_t);
         // update the events
         self.event_store.update_state(delta_t);
         // then update the fixtures
@@ -65,34 +173,363 @@ impl<C: Color> ColorOrgan<C> {
     /// Render the current color for every fixture.
     pub fn render<R: EmitFixtureColor<C>>(&self, mut handler: R) {
         for (id, fixture) in self.fixture_state.iter() {
-            handler(*id, fixture.render());
+            let mode = self.fixture_blend.get(id).copied().unwrap_or(self.blend);
+            handler(*id, fixture.render(mode));
         }
     }
 
+    /// Override the blend mode for a fixture, or clear its override to use the
+    /// organ's blend mode.
+    pub fn set_fixture_blend(&mut self, fixture: FixtureId, mode: Option<BlendMode>) {
+        match mode {
+            Some(mode) => self.fixture_blend.insert(fixture, mode),
+            None => self.fixture_blend.remove(&fixture),
+        };
+    }
+
+    /// Render the current color for every fixture as RGB, using the provided
+    /// gamut mapping for colors that fall outside the sRGB gamut.
+    pub fn render_rgb<R: FnMut(FixtureId, Rgb)>(&self, mapping: GamutMapping, mut handler: R) {
+        self.render(|id, color: C| handler(id, color.rgb(mapping)));
+    }
+
+    /// Set the emitters of a fixture, used to decompose its color into
+    /// channel levels.
+    pub fn set_emitters(&mut self, fixture: FixtureId, emitters: Emitters) {
+        self.emitters.insert(fixture, emitters);
+    }
+
+    /// Set the measured gamut of a fixture, used to match its color to the
+    /// other fixtures.
+    pub fn set_gamut(&mut self, fixture: FixtureId, gamut: FixtureGamut) {
+        self.gamuts.insert(fixture, gamut);
+    }
+
+    /// Render the current channel levels for every fixture, decomposing each
+    /// color according to the fixture's emitters.  Fixtures without emitters
+    /// set are rendered as RGB.
+    /// Fixtures with a measured gamut are mapped into it; the rest are treated
+    /// as sRGB, using the provided gamut mapping.
+    pub fn render_channels<R: FnMut(FixtureId, Vec<UnipolarFloat>)>(
+        &self,
+        mapping: GamutMapping,
+        mut handler: R,
+    ) {
+        let default = Emitters::default();
+        self.render(|id, color: C| {
+            let linear = match self.gamuts.get(&id) {
+                Some(gamut) => gamut.levels(&color),
+                None => color.rgb(mapping).linear(),
+            };
+            let emitters = self.emitters.get(&id).unwrap_or(&default);
+            handler(id, emitters.decompose(linear).channels(emitters.layout()));
+        });
+    }
+
+    /// Sample the envelope that a full-velocity note in the current bank would
+    /// currently produce, held for the provided duration before release.
+    pub fn preview_envelope(&self, held: Duration, interval: Duration) -> EnvelopePreview {
+        EnvelopePreview::sample(
+            self.envelope_gen.generate_with_overrides(
+                UnipolarFloat::ONE,
+                &self.clock,
+                self.banks.envelope_overrides(),
+            ),
+            held,
+            interval,
+        )
+    }
+
     pub fn emit_state<E: EmitStateChange>(&self, emitter: &mut E) {
+        emitter.emit_state_change(StateChange::Retrigger(self.retrigger));
+        emitter.emit_state_change(StateChange::Crossfade(self.crossfade.clone()));
+        emitter.emit_state_change(StateChange::Blend(self.blend));
+        emitter.emit_state_change(StateChange::SustainPedal(self.pedals.sustain()));
+        emitter.emit_state_change(StateChange::Sostenuto(self.pedals.sostenuto()));
+        self.choke_groups.emit_state(emitter);
+        self.clock.emit_state(emitter);
         self.envelope_gen.emit_state(emitter);
+        self.presets.emit_state(emitter);
+        self.modulation.emit_state(emitter);
     }
 
     pub fn control<E: EmitStateChange>(&mut self, msg: ControlMessage, emitter: &mut E) {
         use ControlMessage::*;
         match msg {
-            Envelope(em) => self.envelope_gen.control(em, emitter),
+            Retrigger(mode) => {
+                self.retrigger = mode;
+                emitter.emit_state_change(StateChange::Retrigger(mode));
+            }
+            SustainPedal(down) => {
+                let released = self.pedals.set_sustain(down);
+                self.release_all(released);
+                emitter.emit_state_change(StateChange::SustainPedal(down));
+            }
+            Sostenuto(down) => {
+                let released = self.pedals.set_sostenuto(down);
+                self.release_all(released);
+                emitter.emit_state_change(StateChange::Sostenuto(down));
+            }
+            Crossfade(params) => {
+                self.crossfade = params.clone();
+                emitter.emit_state_change(StateChange::Crossfade(params));
+            }
+            Blend(mode) => {
+                self.blend = mode;
+                emitter.emit_state_change(StateChange::Blend(mode));
+            }
+            Choke(cm) => self.choke_groups.control(cm, emitter),
+            Clock(cm) => self.clock.control(cm, emitter),
+            Envelope(em) => {
+                self.envelope_gen.control(em, emitter);
+                self.presets.update_modified(&self.envelope_gen, emitter);
+            }
+            Preset(pm) => self.presets.control(pm, &mut self.envelope_gen, emitter),
+            Modulation(mm) => self.modulation.control(mm, emitter),
         }
     }
 }
 
+/// Create a new event for the provided note.
+fn new_event<C: Color>(
+    color: C,
+    params: EnvelopeParameters,
+    modulation: Modulation,
+    crossfade: Option<Envelope>,
+    release_id: ReleaseID,
+) -> ColorEventStrong<C> {
+    let mut event =
+        ColorEvent::new(color, Envelope::new(params), release_id).with_modulation(modulation);
+    if let Some(crossfade) = crossfade {
+        event = event.with_crossfade(crossfade);
+    }
+    Rc::new(RefCell::new(event))
+}
+
 pub trait EmitStateChange {
     fn emit_state_change(&mut self, sc: StateChange);
 }
 
+/// An emitter that discards every state change, for use in tests.
+#[cfg(test)]
+pub struct Discard;
+
+#[cfg(test)]
+impl EmitStateChange for Discard {
+    fn emit_state_change(&mut self, _sc: StateChange) {}
+}
+
+/// How a note on is handled when an event with the same release ID is still
+/// sounding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetriggerMode {
+    /// Start a new event, independent of the existing one.
+    Independent,
+    /// Restart the existing event with the new color, with its envelope
+    /// starting from its current level.
+    Restart,
+    /// Change the color of the existing event without affecting its envelope.
+    /// If the existing event has already been released, restart it instead.
+    Legato,
+}
+
 pub enum ControlMessage {
+    Retrigger(RetriggerMode),
+    /// Press or lift the sustain pedal.  Note offs are deferred until the
+    /// pedal lifts.
+    SustainPedal(bool),
+    /// Press or lift the sostenuto pedal.  Note offs for the notes down when
+    /// the pedal was pressed are deferred until it lifts.
+    Sostenuto(bool),
+    /// Set the parameters of the crossfade envelope for new events, or None
+    /// to use their brightness envelope.
+    Crossfade(Option<EnvelopeParameters>),
+    /// Set how overlapping events are combined on fixtures without their
+    /// own blend mode.
+    Blend(BlendMode),
+    Choke(ChokeControlMessage),
+    Clock(ClockControlMessage),
     Envelope(EnvelopeControlMessage),
+    Preset(PresetControlMessage),
+    Modulation(ModulationControlMessage),
 }
 
 pub enum StateChange {
+    Retrigger(RetriggerMode),
+    SustainPedal(bool),
+    Sostenuto(bool),
+    Crossfade(Option<EnvelopeParameters>),
+    Blend(BlendMode),
+    Choke(ChokeStateChange),
+    Clock(ClockStateChange),
     Envelope(EnvelopeStateChange),
+    Preset(PresetStateChange),
+    Modulation(ModulationStateChange),
 }
 
 trait EmitFixtureColor<C: Color>: FnMut(FixtureId, C) {}
 
 impl<T: FnMut(FixtureId, C), C: Color> EmitFixtureColor<C> for T {}
+
+#[cfg(test)]
+mod test {
+    use number::Phase;
+
+    use super::*;
+    use crate::{bank::Bank, choke::ChokeMode, color::HsluvColor};
+
+    /// Return an organ with a single bank, which sends every note to two fixtures.
+    fn organ(retrigger: RetriggerMode) -> ColorOrgan<HsluvColor> {
+        let mut organ = ColorOrgan::new();
+        organ.banks.add(Bank::fixture_set(
+            "test".to_string(),
+            vec![FixtureId(0), FixtureId(1)],
+        ));
+        organ.control(ControlMessage::Retrigger(retrigger), &mut Discard);
+        organ
+    }
+
+    fn color(hue: f64) -> HsluvColor {
+        HsluvColor::new(Phase::new(hue), UnipolarFloat::ONE, UnipolarFloat::ONE)
+    }
+
+    #[test]
+    fn test_retrigger_independent() {
+        let mut organ = organ(RetriggerMode::Independent);
+        organ.note_on(color(0.0), UnipolarFloat::ONE, 0);
+        organ.note_on(color(0.5), UnipolarFloat::ONE, 0);
+        assert_eq!(2, organ.event_store.len());
+    }
+
+    #[test]
+    fn test_retrigger_restart() {
+        let mut organ = organ(RetriggerMode::Restart);
+        organ.note_on(color(0.0), UnipolarFloat::ONE, 0);
+        organ.update_state(Duration::from_millis(500));
+        organ.note_off(0);
+        organ.note_on(color(0.5), UnipolarFloat::ONE, 0);
+        assert_eq!(1, organ.event_store.len());
+        let event = organ.event_store.sounding(0).remove(0);
+        let event = event.borrow();
+        assert!(!event.envelope().released());
+        assert_eq!(Phase::new(0.5), event.value().hue);
+        // The envelope restarted from halfway through the attack.
+        assert_eq!(Some(UnipolarFloat::new(0.5)), event.envelope().value());
+    }
+
+    #[test]
+    fn test_retrigger_legato() {
+        let mut organ = organ(RetriggerMode::Legato);
+        organ.note_on(color(0.0), UnipolarFloat::ONE, 0);
+        organ.update_state(Duration::from_millis(500));
+        organ.note_on(color(0.5), UnipolarFloat::ONE, 0);
+        assert_eq!(1, organ.event_store.len());
+        let event = organ.event_store.sounding(0).remove(0);
+        assert_eq!(Phase::new(0.5), event.borrow().value().hue);
+        // The envelope continues undisturbed.
+        organ.update_state(Duration::from_millis(500));
+        assert_eq!(Some(UnipolarFloat::ONE), event.borrow().envelope().value());
+    }
+
+    #[test]
+    fn test_humanize_per_fixture() {
+        let mut organ = organ(RetriggerMode::Independent);
+        for sc in [
+            EnvelopeStateChange::Humanize(UnipolarFloat::ONE),
+            EnvelopeStateChange::HumanizeSeed(1),
+        ] {
+            organ.control(
+                ControlMessage::Envelope(EnvelopeControlMessage::Set(sc)),
+                &mut Discard,
+            );
+        }
+        // By default, both fixtures share one humanized event.
+        organ.note_on(color(0.0), UnipolarFloat::ONE, 0);
+        assert_eq!(1, organ.event_store.len());
+
+        organ.control(
+            ControlMessage::Envelope(EnvelopeControlMessage::Set(
+                EnvelopeStateChange::HumanizePerFixture(true),
+            )),
+            &mut Discard,
+        );
+        organ.note_on(color(0.0), UnipolarFloat::ONE, 1);
+        let sounding = organ.event_store.sounding(1);
+        assert_eq!(2, sounding.len());
+        organ.update_state(Duration::from_millis(500));
+        assert_ne!(
+            sounding[0].borrow().envelope().value(),
+            sounding[1].borrow().envelope().value()
+        );
+    }
+
+    #[test]
+    fn test_bank_envelope_overrides() {
+        let mut organ: ColorOrgan<HsluvColor> = ColorOrgan::new();
+        organ.banks.add(
+            Bank::fixture_set("pad".to_string(), vec![FixtureId(0)]).with_envelope(vec![
+                EnvelopeStateChange::Attack(UnipolarFloat::new(0.5)),
+                EnvelopeStateChange::Release(UnipolarFloat::ZERO),
+            ]),
+        );
+        organ.note_on(color(0.0), UnipolarFloat::ONE, 0);
+        organ.update_state(Duration::from_millis(500));
+        let event = organ.event_store.sounding(0).remove(0);
+        assert_eq!(Some(UnipolarFloat::ONE), event.borrow().envelope().value());
+
+        // Overridden parameters replace the global ones; the rest are
+        // unchanged.
+        let preview = organ.preview_envelope(Duration::from_secs(2), Duration::from_millis(500));
+        assert_eq!(Duration::from_secs(2), preview.duration());
+    }
+
+    /// Return true if every sounding event with the provided release ID has
+    /// been released.
+    fn released(organ: &ColorOrgan<HsluvColor>, release_id: ReleaseID) -> bool {
+        organ
+            .event_store
+            .sounding(release_id)
+            .iter()
+            .all(|e| e.borrow().envelope().released())
+    }
+
+    #[test]
+    fn test_sustain_pedal() {
+        let mut organ = organ(RetriggerMode::Independent);
+        organ.control(ControlMessage::SustainPedal(true), &mut Discard);
+        organ.note_on(color(0.0), UnipolarFloat::ONE, 0);
+        organ.note_off(0);
+        assert!(!released(&organ, 0));
+        organ.control(ControlMessage::SustainPedal(false), &mut Discard);
+        assert!(released(&organ, 0));
+    }
+
+    #[test]
+    fn test_sostenuto() {
+        let mut organ = organ(RetriggerMode::Independent);
+        organ.note_on(color(0.0), UnipolarFloat::ONE, 0);
+        organ.control(ControlMessage::Sostenuto(true), &mut Discard);
+        organ.note_on(color(0.5), UnipolarFloat::ONE, 1);
+        organ.note_off(0);
+        organ.note_off(1);
+        assert!(!released(&organ, 0));
+        assert!(released(&organ, 1));
+        organ.control(ControlMessage::Sostenuto(false), &mut Discard);
+        assert!(released(&organ, 0));
+    }
+
+    #[test]
+    fn test_choke_group() {
+        let mut organ = organ(RetriggerMode::Independent);
+        for range in &[42..=42, 46..=46] {
+            let msg = ChokeControlMessage::Assign("hats".to_string(), range.clone());
+            organ.control(ControlMessage::Choke(msg), &mut Discard);
+        }
+        let msg = ChokeControlMessage::SetMode("hats".to_string(), ChokeMode::Cut);
+        organ.control(ControlMessage::Choke(msg), &mut Discard);
+        organ.note_on(color(0.0), UnipolarFloat::ONE, 46);
+        organ.note_on(color(0.5), UnipolarFloat::ONE, 42);
+        assert!(organ.event_store.sounding(46).is_empty());
+        assert_eq!(1, organ.event_store.sounding(42).len());
+    }
+}
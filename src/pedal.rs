@@ -0,0 +1,167 @@
+//! Sustain and sostenuto pedal handling for note offs.
+
+use std::collections::HashSet;
+
+use crate::event::ReleaseID;
+
+/// Track the sustain and sostenuto pedals, deferring note offs while notes
+/// are held by a pedal.
+pub struct Pedals {
+    sustain: bool,
+    /// The notes that were down when the sostenuto pedal was pressed, if it
+    /// is down.
+    sostenuto: Option<HashSet<ReleaseID>>,
+    /// The notes whose keys are currently down.
+    down: HashSet<ReleaseID>,
+    /// The notes whose keys have been released while held by a pedal.
+    deferred: HashSet<ReleaseID>,
+}
+
+impl Pedals {
+    pub fn new() -> Self {
+        Self {
+            sustain: false,
+            sostenuto: None,
+            down: HashSet::new(),
+            deferred: HashSet::new(),
+        }
+    }
+
+    /// Return true if the sustain pedal is down.
+    pub fn sustain(&self) -> bool {
+        self.sustain
+    }
+
+    /// Return true if the sostenuto pedal is down.
+    pub fn sostenuto(&self) -> bool {
+        self.sostenuto.is_some()
+    }
+
+    /// Register a note on.
+    /// Striking a note again cancels any deferred note off for it.
+    pub fn note_on(&mut self, release_id: ReleaseID) {
+        self.down.insert(release_id);
+        self.deferred.remove(&release_id);
+    }
+
+    /// Register a note off.
+    /// Return true if the note should be released now, or false if a pedal is
+    /// holding it, in which case it will be released when the pedal lifts.
+    pub fn note_off(&mut self, release_id: ReleaseID) -> bool {
+        self.down.remove(&release_id);
+        if self.held(release_id) {
+            self.deferred.insert(release_id);
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Press or lift the sustain pedal.
+    /// Return the notes that should be released now.
+    pub fn set_sustain(&mut self, down: bool) -> Vec<ReleaseID> {
+        self.sustain = down;
+        self.release_deferred()
+    }
+
+    /// Press or lift the sostenuto pedal.  Pressing the pedal holds only the
+    /// notes that are down at that moment.
+    /// Return the notes that should be released now.
+    pub fn set_sostenuto(&mut self, down: bool) -> Vec<ReleaseID> {
+        if down == self.sostenuto() {
+            return Vec::new();
+        }
+        self.sostenuto = if down { Some(self.down.clone()) } else { None };
+        self.release_deferred()
+    }
+
+    /// Return true if a pedal is holding the provided note.
+    fn held(&self, release_id: ReleaseID) -> bool {
+        self.sustain
+            || self
+                .sostenuto
+                .as_ref()
+                .map(|held| held.contains(&release_id))
+                .unwrap_or(false)
+    }
+
+    /// Remove and return all deferred notes that are no longer held.
+    fn release_deferred(&mut self) -> Vec<ReleaseID> {
+        let mut released: Vec<ReleaseID> = self
+            .deferred
+            .iter()
+            .copied()
+            .filter(|id| !self.held(*id))
+            .collect();
+        released.sort_unstable();
+        for id in &released {
+            self.deferred.remove(id);
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sustain() {
+        let mut pedals = Pedals::new();
+        pedals.note_on(0);
+        assert!(pedals.note_off(0));
+
+        pedals.set_sustain(true);
+        pedals.note_on(1);
+        pedals.note_on(2);
+        assert!(!pedals.note_off(1));
+        assert!(!pedals.note_off(2));
+        // Notes struck after the pedal are also sustained.
+        pedals.note_on(3);
+        assert!(!pedals.note_off(3));
+        assert_eq!(vec![1, 2, 3], pedals.set_sustain(false));
+        assert!(pedals.set_sustain(false).is_empty());
+    }
+
+    #[test]
+    /// Restriking a sustained note cancels its deferred note off.
+    fn test_sustain_restrike() {
+        let mut pedals = Pedals::new();
+        pedals.set_sustain(true);
+        pedals.note_on(0);
+        assert!(!pedals.note_off(0));
+        pedals.note_on(0);
+        assert!(pedals.set_sustain(false).is_empty());
+        assert!(pedals.note_off(0));
+    }
+
+    #[test]
+    fn test_sostenuto() {
+        let mut pedals = Pedals::new();
+        pedals.note_on(0);
+        pedals.set_sostenuto(true);
+        // Only notes that were down when the pedal was pressed are held.
+        pedals.note_on(1);
+        assert!(pedals.note_off(1));
+        assert!(!pedals.note_off(0));
+        // Pressing the pedal again while it is down does not capture new notes.
+        pedals.note_on(2);
+        assert!(pedals.set_sostenuto(true).is_empty());
+        assert!(pedals.note_off(2));
+        assert_eq!(vec![0], pedals.set_sostenuto(false));
+    }
+
+    #[test]
+    /// Notes held by both pedals are released when the last pedal lifts.
+    fn test_both_pedals() {
+        let mut pedals = Pedals::new();
+        pedals.note_on(0);
+        pedals.set_sostenuto(true);
+        pedals.set_sustain(true);
+        pedals.note_on(1);
+        assert!(!pedals.note_off(0));
+        assert!(!pedals.note_off(1));
+        assert_eq!(vec![1], pedals.set_sustain(false));
+        assert_eq!(vec![0], pedals.set_sostenuto(false));
+    }
+}
@@ -0,0 +1,369 @@
+//! Named envelope presets, with morphing between them.
+
+use number::UnipolarFloat;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use crate::color::lerp;
+use crate::envelope_gen::{EmitStateChange as EmitEnvelopeStateChange, EnvelopeGenerator};
+use crate::organ::{EmitStateChange as EmitOrganStateChange, StateChange as OrganStateChange};
+
+/// The envelope generator settings captured by a preset.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EnvelopePreset {
+    pub attack: UnipolarFloat,
+    pub decay: UnipolarFloat,
+    pub sustain_level: UnipolarFloat,
+    pub release: UnipolarFloat,
+    pub time_scale: Duration,
+}
+
+impl EnvelopePreset {
+    /// Interpolate between this preset and another.
+    pub fn lerp(&self, other: &Self, alpha: UnipolarFloat) -> Self {
+        let mix = |a: UnipolarFloat, b: UnipolarFloat| {
+            UnipolarFloat::new(lerp(a.val(), b.val(), alpha.val()))
+        };
+        Self {
+            attack: mix(self.attack, other.attack),
+            decay: mix(self.decay, other.decay),
+            sustain_level: mix(self.sustain_level, other.sustain_level),
+            release: mix(self.release, other.release),
+            time_scale: Duration::from_secs_f64(lerp(
+                self.time_scale.as_secs_f64(),
+                other.time_scale.as_secs_f64(),
+                alpha.val(),
+            )),
+        }
+    }
+}
+
+/// A morph between two named presets.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Morph {
+    pub from: String,
+    pub to: String,
+    pub position: UnipolarFloat,
+}
+
+/// A collection of named envelope presets.
+/// Only the presets and the active preset are persisted.
+#[derive(Serialize, Deserialize)]
+pub struct EnvelopePresets {
+    presets: BTreeMap<String, EnvelopePreset>,
+    /// The name of the last preset saved or recalled.
+    active: Option<String>,
+    /// True if the envelope generator differs from the active preset.
+    #[serde(skip)]
+    modified: bool,
+    #[serde(skip)]
+    morph: Option<Morph>,
+}
+
+impl EnvelopePresets {
+    pub fn new() -> Self {
+        Self {
+            presets: BTreeMap::new(),
+            active: None,
+            modified: false,
+            morph: None,
+        }
+    }
+
+    /// Write these presets as JSON.
+    pub fn save<W: Write>(&self, w: W) -> io::Result<()> {
+        serde_json::to_writer_pretty(w, self).map_err(io::Error::from)
+    }
+
+    /// Read presets written by save.
+    /// The active preset is restored, unmodified and with no morph in progress.
+    pub fn load<R: Read>(r: R) -> io::Result<Self> {
+        serde_json::from_reader(r).map_err(io::Error::from)
+    }
+
+    /// Return the preset with the provided name, if there is one.
+    pub fn get(&self, name: &str) -> Option<&EnvelopePreset> {
+        self.presets.get(name)
+    }
+
+    /// Return the name of the active preset, if there is one.
+    pub fn active(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+
+    /// Return true if the envelope generator has been changed since the
+    /// active preset was saved or recalled.
+    pub fn modified(&self) -> bool {
+        self.modified
+    }
+
+    /// Check whether the envelope generator differs from the active preset,
+    /// emitting a state change if this has changed.
+    pub fn update_modified<E: EmitStateChange>(
+        &mut self,
+        envelope_gen: &EnvelopeGenerator,
+        emitter: &mut E,
+    ) {
+        let modified = match self.active.as_ref().and_then(|name| self.presets.get(name)) {
+            Some(preset) => *preset != envelope_gen.preset(),
+            None => false,
+        };
+        if modified != self.modified {
+            self.modified = modified;
+            emitter.emit_preset_state_change(StateChange::Modified(modified));
+        }
+    }
+
+    /// Emit all observable state using the provided emitter.
+    pub fn emit_state<E: EmitStateChange>(&self, emitter: &mut E) {
+        use StateChange::*;
+        emitter.emit_preset_state_change(Presets(self.presets.keys().cloned().collect()));
+        emitter.emit_preset_state_change(Active(self.active.clone()));
+        emitter.emit_preset_state_change(Modified(self.modified));
+        emitter.emit_preset_state_change(Morph(self.morph.clone()));
+    }
+
+    /// Handle a control message.
+    /// Recalling and morphing presets changes the envelope generator.
+    pub fn control<E: EmitStateChange + EmitEnvelopeStateChange>(
+        &mut self,
+        msg: ControlMessage,
+        envelope_gen: &mut EnvelopeGenerator,
+        emitter: &mut E,
+    ) {
+        use ControlMessage::*;
+        match msg {
+            Save(name) => {
+                self.presets.insert(name.clone(), envelope_gen.preset());
+                emitter.emit_preset_state_change(StateChange::Presets(
+                    self.presets.keys().cloned().collect(),
+                ));
+                self.set_active(Some(name), emitter);
+            }
+            Recall(name) => {
+                if let Some(preset) = self.presets.get(&name) {
+                    envelope_gen.apply_preset(preset, emitter);
+                    self.set_active(Some(name), emitter);
+                }
+            }
+            Delete(name) => {
+                if self.presets.remove(&name).is_none() {
+                    return;
+                }
+                emitter.emit_preset_state_change(StateChange::Presets(
+                    self.presets.keys().cloned().collect(),
+                ));
+                if self.active.as_ref() == Some(&name) {
+                    self.set_active(None, emitter);
+                }
+            }
+            MorphBetween(from, to) => {
+                if !self.presets.contains_key(&from) || !self.presets.contains_key(&to) {
+                    return;
+                }
+                self.morph = Some(Morph {
+                    from,
+                    to,
+                    position: UnipolarFloat::ZERO,
+                });
+                self.apply_morph(envelope_gen, emitter);
+            }
+            MorphPosition(position) => {
+                if let Some(morph) = self.morph.as_mut() {
+                    morph.position = position;
+                    self.apply_morph(envelope_gen, emitter);
+                }
+            }
+        }
+        self.update_modified(envelope_gen, emitter);
+    }
+
+    fn set_active<E: EmitStateChange>(&mut self, active: Option<String>, emitter: &mut E) {
+        self.active = active;
+        self.modified = false;
+        emitter.emit_preset_state_change(StateChange::Active(self.active.clone()));
+        emitter.emit_preset_state_change(StateChange::Modified(false));
+    }
+
+    /// Set the envelope generator to the current position of the morph.
+    fn apply_morph<E: EmitStateChange + EmitEnvelopeStateChange>(
+        &self,
+        envelope_gen: &mut EnvelopeGenerator,
+        emitter: &mut E,
+    ) {
+        let morph = match self.morph.as_ref() {
+            Some(morph) => morph,
+            None => return,
+        };
+        if let (Some(from), Some(to)) = (self.presets.get(&morph.from), self.presets.get(&morph.to))
+        {
+            envelope_gen.apply_preset(&from.lerp(to, morph.position), emitter);
+        }
+        emitter.emit_preset_state_change(StateChange::Morph(Some(morph.clone())));
+    }
+}
+
+pub enum ControlMessage {
+    /// Save the current envelope generator settings under a name, replacing
+    /// any preset with the same name.
+    Save(String),
+    Recall(String),
+    Delete(String),
+    /// Start morphing from one preset to another, from the first preset.
+    MorphBetween(String, String),
+    /// Set the position of the current morph.
+    MorphPosition(UnipolarFloat),
+}
+
+pub enum StateChange {
+    /// The names of all stored presets.
+    Presets(Vec<String>),
+    Active(Option<String>),
+    /// True if the envelope has unsaved modifications to the active preset.
+    Modified(bool),
+    Morph(Option<Morph>),
+}
+
+pub trait EmitStateChange {
+    fn emit_preset_state_change(&mut self, sc: StateChange);
+}
+
+impl<T: EmitOrganStateChange> EmitStateChange for T {
+    fn emit_preset_state_change(&mut self, sc: StateChange) {
+        self.emit_state_change(OrganStateChange::Preset(sc));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::envelope_gen::{
+        ControlMessage as EnvelopeControlMessage, StateChange as EnvelopeStateChange,
+    };
+
+    /// Record only the preset state changes.
+    struct Record(Vec<StateChange>);
+
+    impl EmitStateChange for Record {
+        fn emit_preset_state_change(&mut self, sc: StateChange) {
+            self.0.push(sc);
+        }
+    }
+
+    impl EmitEnvelopeStateChange for Record {
+        fn emit_envelope_generator_state_change(&mut self, _sc: EnvelopeStateChange) {}
+    }
+
+    fn set_attack(gen: &mut EnvelopeGenerator, attack: f64) {
+        gen.control(
+            EnvelopeControlMessage::Set(EnvelopeStateChange::Attack(UnipolarFloat::new(attack))),
+            &mut Record(Vec::new()),
+        );
+    }
+
+    #[test]
+    fn test_save_recall() {
+        let mut presets = EnvelopePresets::new();
+        let mut gen = EnvelopeGenerator::new();
+        let mut emitter = Record(Vec::new());
+        set_attack(&mut gen, 0.1);
+        presets.control(
+            ControlMessage::Save("snap".to_string()),
+            &mut gen,
+            &mut emitter,
+        );
+        set_attack(&mut gen, 0.9);
+        presets.control(
+            ControlMessage::Save("swell".to_string()),
+            &mut gen,
+            &mut emitter,
+        );
+        assert_eq!(Some("swell"), presets.active());
+
+        presets.control(
+            ControlMessage::Recall("snap".to_string()),
+            &mut gen,
+            &mut emitter,
+        );
+        assert_eq!(UnipolarFloat::new(0.1), gen.preset().attack);
+        assert_eq!(Some("snap"), presets.active());
+        assert!(!presets.modified());
+
+        // Changing the envelope is an unsaved modification.
+        set_attack(&mut gen, 0.5);
+        let mut emitter = Record(Vec::new());
+        presets.update_modified(&gen, &mut emitter);
+        assert!(presets.modified());
+        assert!(matches!(emitter.0[..], [StateChange::Modified(true)]));
+
+        // Recalling a preset that doesn't exist has no effect.
+        presets.control(
+            ControlMessage::Recall("none".to_string()),
+            &mut gen,
+            &mut emitter,
+        );
+        assert_eq!(Some("snap"), presets.active());
+        assert_eq!(UnipolarFloat::new(0.5), gen.preset().attack);
+    }
+
+    #[test]
+    fn test_morph() {
+        let mut presets = EnvelopePresets::new();
+        let mut gen = EnvelopeGenerator::new();
+        let mut emitter = Record(Vec::new());
+        set_attack(&mut gen, 0.2);
+        presets.control(
+            ControlMessage::Save("a".to_string()),
+            &mut gen,
+            &mut emitter,
+        );
+        set_attack(&mut gen, 0.6);
+        presets.control(
+            ControlMessage::Save("b".to_string()),
+            &mut gen,
+            &mut emitter,
+        );
+
+        presets.control(
+            ControlMessage::MorphBetween("a".to_string(), "b".to_string()),
+            &mut gen,
+            &mut emitter,
+        );
+        assert_eq!(UnipolarFloat::new(0.2), gen.preset().attack);
+        presets.control(
+            ControlMessage::MorphPosition(UnipolarFloat::new(0.5)),
+            &mut gen,
+            &mut emitter,
+        );
+        assert!((gen.preset().attack.val() - 0.4).abs() < 1e-9);
+        // Morphed settings differ from the active preset.
+        assert!(presets.modified());
+    }
+
+    #[test]
+    fn test_save_load() {
+        let mut presets = EnvelopePresets::new();
+        let mut gen = EnvelopeGenerator::new();
+        let mut emitter = Record(Vec::new());
+        set_attack(&mut gen, 0.3);
+        presets.control(
+            ControlMessage::Save("snap".to_string()),
+            &mut gen,
+            &mut emitter,
+        );
+        set_attack(&mut gen, 0.7);
+        presets.update_modified(&gen, &mut emitter);
+        assert!(presets.modified());
+
+        let mut saved = Vec::new();
+        presets.save(&mut saved).unwrap();
+        let loaded = EnvelopePresets::load(&saved[..]).unwrap();
+        assert_eq!(presets.get("snap"), loaded.get("snap"));
+        assert_eq!(Some("snap"), loaded.active());
+        assert!(!loaded.modified());
+
+        assert!(EnvelopePresets::load(&b"not json"[..]).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! Render envelopes as tables of values for previews and plots.
+
+use number::UnipolarFloat;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::breakpoint::Breakpoints;
+use crate::envelope::Envelope;
+
+/// Stop sampling after this many samples, in case an envelope never closes.
+const MAX_SAMPLES: usize = 100_000;
+
+/// A single point in an envelope preview.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    /// Time since note on.
+    pub time: Duration,
+    pub value: UnipolarFloat,
+}
+
+/// A table of envelope values sampled at regular intervals.
+#[derive(Clone, Debug)]
+pub struct EnvelopePreview {
+    pub samples: Vec<Sample>,
+}
+
+impl EnvelopePreview {
+    /// Sample an envelope that is held for the provided duration before being
+    /// released.
+    /// Samples are taken at the provided interval until the envelope closes,
+    /// followed by a final sample at the exact time the envelope closed, with
+    /// a value of zero.
+    pub fn sample<B: Into<Breakpoints>>(envelope: B, held: Duration, interval: Duration) -> Self {
+        let mut envelope = Envelope::new(envelope);
+        envelope.set_release_time(Some(held));
+        let mut samples = Vec::new();
+        let mut time = Duration::from_secs(0);
+        while samples.len() < MAX_SAMPLES {
+            match envelope.value_at(time) {
+                Some(value) => samples.push(Sample { time, value }),
+                None => {
+                    // The envelope closed since the last sample, so end
+                    // exactly at the close rather than on the next interval.
+                    let close = envelope
+                        .total_duration()
+                        .map_or(time, |close| close.min(time));
+                    samples.push(Sample {
+                        time: close,
+                        value: UnipolarFloat::ZERO,
+                    });
+                    break;
+                }
+            }
+            if interval == Duration::from_secs(0) {
+                break;
+            }
+            time += interval;
+        }
+        Self { samples }
+    }
+
+    /// Return the time of the last sample.
+    pub fn duration(&self) -> Duration {
+        self.samples
+            .last()
+            .map(|s| s.time)
+            .unwrap_or_else(|| Duration::from_secs(0))
+    }
+
+    /// Write this preview as CSV, with time in seconds.
+    pub fn write_csv<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "time,value")?;
+        for sample in &self.samples {
+            writeln!(w, "{},{}", sample.time.as_secs_f64(), sample.value.val())?;
+        }
+        Ok(())
+    }
+
+    /// Write this preview as an SVG plot with the provided dimensions.
+    /// Time runs from left to right and the full envelope range fills the
+    /// height of the plot.
+    pub fn write_svg<W: Write>(&self, mut w: W, width: f64, height: f64) -> io::Result<()> {
+        let duration = self.duration().as_secs_f64();
+        writeln!(
+            w,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#,
+            width = width,
+            height = height,
+        )?;
+        write!(w, r#"<polyline fill="none" stroke="black" points=""#)?;
+        for (i, sample) in self.samples.iter().enumerate() {
+            let x = if duration == 0. {
+                0.
+            } else {
+                width * sample.time.as_secs_f64() / duration
+            };
+            let y = height * (1. - sample.value.val());
+            if i > 0 {
+                write!(w, " ")?;
+            }
+            write!(w, "{:.3},{:.3}", x, y)?;
+        }
+        writeln!(w, r#""/>"#)?;
+        writeln!(w, "</svg>")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::envelope::EnvelopeParameters;
+
+    fn preview() -> EnvelopePreview {
+        preview_every(Duration::from_millis(500))
+    }
+
+    fn preview_every(interval: Duration) -> EnvelopePreview {
+        EnvelopePreview::sample(
+            EnvelopeParameters::linear(
+                Duration::from_secs(1),
+                UnipolarFloat::ZERO,
+                Duration::from_secs(1),
+                UnipolarFloat::new(0.5),
+                Duration::from_secs(1),
+            ),
+            Duration::from_secs(3),
+            interval,
+        )
+    }
+
+    #[test]
+    fn test_sample() {
+        let values: Vec<f64> = preview().samples.iter().map(|s| s.value.val()).collect();
+        assert_eq!(vec![0.0, 0.5, 1.0, 0.75, 0.5, 0.5, 0.5, 0.25, 0.0], values);
+        assert_eq!(Duration::from_secs(4), preview().duration());
+    }
+
+    #[test]
+    /// The last sample lands on the close even when it falls between intervals.
+    fn test_sample_close() {
+        let preview = preview_every(Duration::from_millis(300));
+        let last = preview.samples.last().unwrap();
+        assert_eq!(Duration::from_secs(4), last.time);
+        assert_eq!(UnipolarFloat::ZERO, last.value);
+        assert_eq!(Duration::from_secs(4), preview.duration());
+
+        // An interval longer than the envelope still spans the whole envelope.
+        let preview = preview_every(Duration::from_secs(10));
+        assert_eq!(2, preview.samples.len());
+        assert_eq!(Duration::from_secs(4), preview.duration());
+    }
+
+    #[test]
+    fn test_csv() {
+        let mut buf = Vec::new();
+        preview().write_csv(&mut buf).unwrap();
+        let csv = String::from_utf8(buf).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!("time,value", lines[0]);
+        assert_eq!("0.5,0.5", lines[2]);
+        assert_eq!(10, lines.len());
+    }
+
+    #[test]
+    fn test_svg() {
+        let mut buf = Vec::new();
+        preview().write_svg(&mut buf, 400., 100.).unwrap();
+        let svg = String::from_utf8(buf).unwrap();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains("0.000,100.000 50.000,50.000 100.000,0.000"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+    }
+}
@@ -0,0 +1,259 @@
+//! Conversion of rendered colors into RGB output values.
+
+use number::UnipolarFloat;
+use serde::{Deserialize, Serialize};
+
+use crate::color::{HsluvColor, HsvColor};
+use crate::hsluv;
+
+/// The relative luminance of each linear sRGB primary.
+const LUMINANCE: [f64; 3] = [0.21263900587151, 0.71516867876775, 0.072192315360733];
+
+/// How to bring a color that falls outside the sRGB gamut back inside it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GamutMapping {
+    /// Clamp each channel independently.  This may shift the hue.
+    Clip,
+    /// Blend the color toward the gray of the same luminance until it fits,
+    /// preserving hue and luminance where possible.
+    Desaturate,
+}
+
+/// A color as gamma-encoded sRGB channel levels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rgb {
+    pub red: UnipolarFloat,
+    pub green: UnipolarFloat,
+    pub blue: UnipolarFloat,
+}
+
+impl Rgb {
+    pub const BLACK: Self = Self {
+        red: UnipolarFloat::ZERO,
+        green: UnipolarFloat::ZERO,
+        blue: UnipolarFloat::ZERO,
+    };
+
+    /// Create a color from gamma-encoded channel levels.
+    pub fn new(red: f64, green: f64, blue: f64) -> Self {
+        Self {
+            red: UnipolarFloat::new(red),
+            green: UnipolarFloat::new(green),
+            blue: UnipolarFloat::new(blue),
+        }
+    }
+
+    /// Create a color from linear sRGB components, which may fall outside the
+    /// gamut, using the provided gamut mapping.
+    pub fn from_linear(linear: [f64; 3], mapping: GamutMapping) -> Self {
+        let [r, g, b] = match mapping {
+            GamutMapping::Clip => linear,
+            GamutMapping::Desaturate => desaturate(linear),
+        };
+        // Clamping also catches any rounding error left by the mapping.
+        let [r, g, b] = gamma_encode([r, g, b]);
+        Self::new(r, g, b)
+    }
+
+    /// Return the linear sRGB components of this color.
+    pub fn linear(&self) -> [f64; 3] {
+        [
+            hsluv::to_linear(self.red.val()),
+            hsluv::to_linear(self.green.val()),
+            hsluv::to_linear(self.blue.val()),
+        ]
+    }
+
+    /// Quantize this color to 8-bit channel values.
+    pub fn to_u8(self) -> [u8; 3] {
+        let q = |c: UnipolarFloat| (c.val() * u8::MAX as f64).round() as u8;
+        [q(self.red), q(self.green), q(self.blue)]
+    }
+
+    /// Quantize this color to 16-bit channel values.
+    pub fn to_u16(self) -> [u16; 3] {
+        let q = |c: UnipolarFloat| (c.val() * u16::MAX as f64).round() as u16;
+        [q(self.red), q(self.green), q(self.blue)]
+    }
+}
+
+/// Blend linear sRGB components toward the gray of the same luminance just
+/// enough to bring every component inside the gamut.
+/// Colors brighter than white become white, and darker than black become black.
+fn desaturate(linear: [f64; 3]) -> [f64; 3] {
+    let y: f64 = linear
+        .iter()
+        .zip(LUMINANCE.iter())
+        .map(|(c, l)| c * l)
+        .sum();
+    if y >= 1. {
+        return [1., 1., 1.];
+    }
+    if y <= 0. {
+        return [0., 0., 0.];
+    }
+    let t = linear
+        .iter()
+        .map(|&c| {
+            if c > 1. {
+                (1. - y) / (c - y)
+            } else if c < 0. {
+                y / (y - c)
+            } else {
+                1.
+            }
+        })
+        .fold(1., f64::min);
+    let mix = |c: f64| y + t * (c - y);
+    [mix(linear[0]), mix(linear[1]), mix(linear[2])]
+}
+
+/// A color that can be converted to and from RGB.
+pub trait RgbColor: Sized {
+    /// Return this color as linear sRGB components.
+    /// Components may fall outside [0, 1] if the color is out of gamut.
+    fn linear_rgb(&self) -> [f64; 3];
+
+    /// Create a color from linear sRGB components in the range [0, 1].
+    fn from_linear_rgb(linear: [f64; 3]) -> Self;
+
+    /// Return this color as gamma-encoded sRGB, using the provided gamut mapping.
+    fn rgb(&self, mapping: GamutMapping) -> Rgb {
+        Rgb::from_linear(self.linear_rgb(), mapping)
+    }
+}
+
+impl RgbColor for HsluvColor {
+    fn linear_rgb(&self) -> [f64; 3] {
+        hsluv::xyz_to_linear_rgb(hsluv::luv_to_xyz(hsluv::lch_to_luv(hsluv::hsluv_to_lch(
+            self.components(),
+        ))))
+    }
+
+    fn from_linear_rgb(linear: [f64; 3]) -> Self {
+        Self::from_components(hsluv::rgb_to_hsluv(gamma_encode(linear)))
+    }
+}
+
+impl RgbColor for HsvColor {
+    fn linear_rgb(&self) -> [f64; 3] {
+        let [r, g, b] = self.to_rgb();
+        [
+            hsluv::to_linear(r),
+            hsluv::to_linear(g),
+            hsluv::to_linear(b),
+        ]
+    }
+
+    fn from_linear_rgb(linear: [f64; 3]) -> Self {
+        Self::from_rgb(gamma_encode(linear))
+    }
+}
+
+/// Apply the sRGB transfer function to linear components, clamped to the
+/// unit range.
+fn gamma_encode(linear: [f64; 3]) -> [f64; 3] {
+    [
+        hsluv::from_linear(linear[0].clamp(0., 1.)),
+        hsluv::from_linear(linear[1].clamp(0., 1.)),
+        hsluv::from_linear(linear[2].clamp(0., 1.)),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use number::Phase;
+
+    use super::*;
+
+    fn hsluv(h: f64, s: f64, l: f64) -> HsluvColor {
+        HsluvColor::new(
+            Phase::new(h / 360.),
+            UnipolarFloat::new(s / 100.),
+            UnipolarFloat::new(l / 100.),
+        )
+    }
+
+    #[test]
+    /// Reference values from the HSLuv reference implementation.
+    fn test_reference_values() {
+        for &((h, s, l), expected) in &[
+            ((12.177050630061776, 100., 53.23711559542933), [255, 0, 0]),
+            ((127.71501294924047, 100., 87.73551910965973), [0, 255, 0]),
+            ((265.8743202181779, 100., 32.30087290398002), [0, 0, 255]),
+            ((85.87432021817789, 100., 97.13824698129729), [255, 255, 0]),
+            ((0., 0., 53.585013452169036), [128, 128, 128]),
+            ((0., 0., 100.), [255, 255, 255]),
+            ((0., 0., 0.), [0, 0, 0]),
+        ] {
+            assert_eq!(
+                expected,
+                hsluv(h, s, l).rgb(GamutMapping::Clip).to_u8(),
+                "HSLuv ({}, {}, {})",
+                h,
+                s,
+                l
+            );
+        }
+    }
+
+    #[test]
+    fn test_quantize() {
+        let color = Rgb::new(1., 0.5, 0.);
+        assert_eq!([255, 128, 0], color.to_u8());
+        assert_eq!([65535, 32768, 0], color.to_u16());
+    }
+
+    #[test]
+    fn test_hsv() {
+        let color = HsvColor::new(Phase::ZERO, UnipolarFloat::ONE, UnipolarFloat::new(0.5));
+        assert_eq!([128, 0, 0], color.rgb(GamutMapping::Clip).to_u8());
+    }
+
+    #[test]
+    fn test_from_linear_rgb() {
+        let linear = [0.2, 0.4, 0.6];
+        for actual in &[
+            HsluvColor::from_linear_rgb(linear).linear_rgb(),
+            HsvColor::from_linear_rgb(linear).linear_rgb(),
+        ] {
+            for (e, a) in linear.iter().zip(actual.iter()) {
+                assert!(
+                    (e - a).abs() < 1e-6,
+                    "expected {:?}, got {:?}",
+                    linear,
+                    actual
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_gamut_mapping() {
+        let out_of_gamut = [1.5, 0.5, -0.2];
+        assert_eq!(
+            Rgb::new(1., hsluv::from_linear(0.5), 0.).to_u16(),
+            Rgb::from_linear(out_of_gamut, GamutMapping::Clip).to_u16()
+        );
+
+        // Desaturating keeps the luminance and the order of the channels.
+        let mapped = Rgb::from_linear(out_of_gamut, GamutMapping::Desaturate).linear();
+        let luminance = |c: [f64; 3]| c.iter().zip(LUMINANCE.iter()).map(|(c, l)| c * l).sum();
+        let expected: f64 = luminance(out_of_gamut);
+        assert!((expected - luminance(mapped)).abs() < 1e-6);
+        assert!((1. - mapped[0]).abs() < 1e-6);
+        assert!(mapped[0] > mapped[1] && mapped[1] > mapped[2]);
+
+        // In-gamut colors are unchanged.
+        assert_eq!(
+            Rgb::from_linear([0.2, 0.4, 0.6], GamutMapping::Clip),
+            Rgb::from_linear([0.2, 0.4, 0.6], GamutMapping::Desaturate)
+        );
+
+        // Too bright to fit at all.
+        assert_eq!(
+            [u16::MAX; 3],
+            Rgb::from_linear([3., 2., 1.5], GamutMapping::Desaturate).to_u16()
+        );
+    }
+}
@@ -8,6 +8,7 @@ use std::{
 };
 
 use crate::{
+    choke::{ChokeGroup, ChokeMode},
     color::Color,
     event::{ColorEvent, ReleaseID},
 };
@@ -39,6 +40,47 @@ impl<C: Color> ColorEventStore<C> {
         }
     }
 
+    /// Release or cut all events in the provided choke group, except those
+    /// with the provided release ID.
+    pub fn choke(&mut self, group: &ChokeGroup, except: ReleaseID) {
+        for event in self.0.iter() {
+            if let Some(e) = event.upgrade() {
+                let mut e = e.borrow_mut();
+                let release_id = e.release_id();
+                if release_id == except || !group.contains(release_id) {
+                    continue;
+                }
+                match group.mode() {
+                    ChokeMode::Release => e.release(release_id),
+                    ChokeMode::Cut => e.cut(),
+                }
+            }
+        }
+    }
+
+    /// Return all events with the given release ID that have not yet closed,
+    /// oldest first.
+    pub fn sounding(&self, release_id: ReleaseID) -> Vec<ColorEventStrong<C>> {
+        self.0
+            .iter()
+            .filter_map(|e| e.upgrade())
+            .filter(|e| {
+                let e = e.borrow();
+                e.release_id() == release_id && !e.envelope().closed()
+            })
+            .collect()
+    }
+
+    /// Return the number of events in this store that are still alive.
+    pub fn len(&self) -> usize {
+        self.0.iter().filter(|e| e.strong_count() > 0).count()
+    }
+
+    /// Return true if this store has no live events.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Update the state of all the events in this store.
     pub fn update_state(&mut self, delta_t: Duration) {
         self.clean();
@@ -86,6 +128,56 @@ mod test {
         assert!(!event_1.borrow().envelope().released());
     }
 
+    #[test]
+    fn test_choke() {
+        let mut store = ColorEventStore::new();
+        let open = mkevent(46);
+        let closed = mkevent(42);
+        let other = mkevent(36);
+        for event in &[&open, &closed, &other] {
+            store.add(event);
+        }
+        store.choke(&ChokeGroup::new(&[42, 46], ChokeMode::Release), 42);
+        assert!(open.borrow().envelope().released());
+        assert!(!closed.borrow().envelope().released());
+        assert!(!other.borrow().envelope().released());
+
+        store.choke(&ChokeGroup::new(&[36, 42], ChokeMode::Cut), 42);
+        assert!(other.borrow().envelope().closed());
+        assert!(!closed.borrow().envelope().closed());
+    }
+
+    #[test]
+    fn test_sounding() {
+        let mut store = ColorEventStore::new();
+        let event_0 = mkevent(0);
+        let event_1 = mkevent(0);
+        store.add(&event_0);
+        store.add(&event_1);
+        assert_eq!(2, store.len());
+        {
+            let sounding = store.sounding(0);
+            assert_eq!(2, sounding.len());
+            assert!(Rc::ptr_eq(&event_0, &sounding[0]));
+            assert!(Rc::ptr_eq(&event_1, &sounding[1]));
+        }
+        assert!(store.sounding(1).is_empty());
+
+        // Closed events are no longer sounding.
+        store.release(0);
+        event_1.borrow_mut().update_state(Duration::from_secs(10));
+        {
+            let sounding = store.sounding(0);
+            assert_eq!(1, sounding.len());
+            assert!(Rc::ptr_eq(&event_0, &sounding[0]));
+        }
+
+        // Dropped events are no longer in the store.
+        drop(event_0);
+        assert_eq!(1, store.len());
+        assert!(store.sounding(0).is_empty());
+    }
+
     fn envelope() -> Envelope {
         Envelope::new(EnvelopeParameters::linear(
             Duration::from_secs(1),